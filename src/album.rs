@@ -0,0 +1,61 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::cluster;
+use crate::db;
+use crate::error::Error;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Number of tracks, and sum of each analysis value, of an album
+struct AlbumSum {
+    tracks: usize,
+    sums: Vec<f64>,
+}
+
+fn write_csv(out: &str, albums: &BTreeMap<(String, String), AlbumSum>) -> Result<(), Error> {
+    let res = File::create(out).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "AlbumArtist,Album,Tracks,{}", db::ANALYSIS_COLUMNS.replace(" ", ""))?;
+        for ((artist, album), sum) in albums {
+            let vals: Vec<String> = sum.sums.iter().map(|s| (s / sum.tracks as f64).to_string()).collect();
+            writeln!(writer, "{},{},{},{}", cluster::csv_field(artist), cluster::csv_field(album), sum.tracks, vals.join(","))?;
+        }
+        writer.flush()
+    });
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::Config(format!("Failed to write CSV file ({}). {}", out, e))),
+    }
+}
+
+/// Average analysis of the tracks of each album, and write to 'out' as CSV. Albums
+/// are grouped by album artist (or artist, if not set) and album. Ignored tracks, and
+/// tracks without an album, are not included.
+pub fn create(db_path: &str, out: &str) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    let tracks = db.get_album_analysis();
+    db.close();
+
+    let mut albums: BTreeMap<(String, String), AlbumSum> = BTreeMap::new();
+    for (artist, album, analysis) in tracks {
+        let entry = albums.entry((artist, album)).or_insert_with(|| AlbumSum { tracks: 0, sums: vec![0.0; analysis.len()] });
+        entry.tracks += 1;
+        for (sum, val) in entry.sums.iter_mut().zip(analysis.iter()) {
+            *sum += val;
+        }
+    }
+    if albums.is_empty() {
+        return Err(Error::Config(String::from("No albums found in DB")));
+    }
+
+    write_csv(out, &albums)?;
+    log::info!("Aggregated {} album(s), written to {}", albums.len(), out);
+    Ok(())
+}
@@ -6,270 +6,1967 @@
  *
  **/
 
+use crate::cue;
 use crate::db;
+use crate::error::{self, Error};
+use crate::lock;
+use crate::logging;
+use crate::notify;
+use crate::report;
+use crate::scancache::{self, ScanCache};
+use crate::status;
 use crate::tags;
 use anyhow::Result;
 use bliss_audio::decoder::{Decoder, ffmpeg::FFmpeg};
+use chrono::{Local, TimeZone};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use if_chain::if_chain;
 use indicatif::{ProgressBar, ProgressStyle};
-use std::collections::HashSet;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
-use std::fs::{DirEntry, File};
-use std::io::{BufRead, BufReader};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, BufRead, BufReader};
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use num_cpus;
+use serde_json::json;
 
-const DONT_ANALYSE: &str = ".notmusic";
-const MAX_ERRORS_TO_SHOW: usize = 100;
-const MAX_TAG_ERRORS_TO_SHOW: usize = 50;
-const VALID_EXTENSIONS: [&str; 6] = ["m4a", "mp3", "ogg", "flac", "opus", "wv"];
+pub const DEFAULT_SKIP_MARKER: &str = ".notmusic";
+pub const MAX_ERRORS_TO_SHOW: usize = 100;
+pub const MAX_TAG_ERRORS_TO_SHOW: usize = 50;
+// Files smaller than this cannot contain any meaningful audio
+const MIN_FILE_SIZE: u64 = 1024;
+// Folders created by operating systems, or NAS devices, which never contain music
+const JUNK_FOLDERS: [&str; 7] = ["@eaDir", ".@__thumb", "#recycle", "#snapshot", "System Volume Information", "$RECYCLE.BIN", "lost+found"];
+const PROGRESS_LOG_TIME: Duration = Duration::from_secs(30);
+// Number of most recently analysed files used to calculate analysis rate
+const RATE_WINDOW: usize = 50;
+// Number of slowest files to show after analysis
+const NUM_SLOWEST: usize = 10;
+pub const DEFAULT_EXTENSIONS: [&str; 11] = ["m4a", "mp3", "ogg", "flac", "opus", "wv", "wav", "aif", "aiff", "dsf", "dff"];
+// Extensions of audio formats that ffmpeg should be able to decode
+const DECODABLE_EXTENSIONS: [&str; 20] = ["m4a", "mp3", "ogg", "flac", "opus", "wv", "wav", "aif", "aiff", "ape", "wma", "aac",
+                                          "mp4", "mpc", "dsf", "dff", "oga", "tta", "mka", "webm"];
 
-fn get_file_list(db: &mut db::Db, mpath: &Path, path: &Path, track_paths: &mut Vec<String>) {
+/// Set to stop analysis once the current file has been handled.
+pub static TERMINATE_ANALYSIS_FLAG: AtomicBool = AtomicBool::new(false);
+
+pub fn terminated() -> bool {
+    TERMINATE_ANALYSIS_FLAG.load(Ordering::Relaxed)
+}
+
+/// Handle Ctrl-C and SIGTERM by stopping once the current file has been analysed. A
+/// second signal exits immediately.
+pub fn stop_on_signal() {
+    if let Err(e) = ctrlc::set_handler(|| {
+        if terminated() {
+            log::info!("Terminated by user");
+            lock::release();
+            std::process::exit(error::EXIT_TERMINATED);
+        }
+        log::info!("Stopping, please wait...");
+        TERMINATE_ANALYSIS_FLAG.store(true, Ordering::Relaxed);
+    }) {
+        log::error!("Failed to install signal handler. {}", e);
+        std::process::exit(-1);
+    }
+}
+
+/// Settings controlling how analysis is performed.
+pub struct Options {
+    pub dry_run: bool,
+    // File to write dry run report to
+    pub report_file: String,
+    pub keep_old: bool,
+    // Maximum percentage of tracks that may be removed as non-existent, unless forced
+    pub max_remove_percent: usize,
+    pub force_remove: bool,
+    // (from, to) path prefixes of tracks that have moved
+    pub moved_from: Vec<(String, String)>,
+    pub max_num_files: usize,
+    pub max_threads: usize,
+    pub max_errors: usize,
+    pub max_tag_errors: usize,
+    pub errors_file: String,
+    pub show_progress: bool,
+    pub progress_json: bool,
+    pub max_consecutive_failures: usize,
+    pub status_file: String,
+    pub retry_failed: bool,
+    pub exclude: GlobSet,
+    // Patterns used to build 'exclude', in the same order
+    pub exclude_patterns: Vec<String>,
+    pub force: bool,
+    pub skip_markers: Vec<String>,
+    pub refresh_stale: bool,
+    pub extensions: Vec<String>,
+    pub order: Order,
+    pub min_duration: u32,
+    pub max_duration: u32,
+    pub follow_symlinks: bool,
+    pub include_hidden: bool,
+    // File to cache folder listings in, empty if not cached
+    pub scan_cache: String,
+    // Only consider files modified within this many seconds, 0 for all files
+    pub since: u64,
+}
+
+pub const ORDER_NAMES: &str = "path, shortest, largest, random";
+
+/// Order in which new files are analysed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Order {
+    Path,
+    Shortest,
+    Largest,
+    Random,
+}
+
+impl Order {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "path" => Some(Order::Path),
+            "shortest" => Some(Order::Shortest),
+            "largest" => Some(Order::Largest),
+            "random" => Some(Order::Random),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Order::Path => "path",
+            Order::Shortest => "shortest",
+            Order::Largest => "largest",
+            Order::Random => "random",
+        }
+    }
+}
+
+/// Totals across all music folders.
+#[derive(Default)]
+pub struct Summary {
+    pub analysed: usize,
+    pub failed: usize,
+    pub tag_errors: usize,
+    pub errors: Vec<(String, String)>,
+    pub aborted: bool,
+    pub discovered: usize,
+    pub attempted: usize,
+    pub skipped: usize,
+}
+
+impl Summary {
+    /// Number of files found, but not analysed due to file limit, abort, or termination.
+    pub fn not_attempted(&self) -> usize {
+        self.discovered - self.attempted
+    }
+
+    fn log(&self, title: &str) {
+        log::info!(target: logging::SUMMARY, "{}", title);
+        log::info!(target: logging::SUMMARY, "  Discovered:    {}", self.discovered);
+        log::info!(target: logging::SUMMARY, "  Analysed:      {}", self.analysed);
+        log::info!(target: logging::SUMMARY, "  Failed:        {}", self.failed);
+        log::info!(target: logging::SUMMARY, "  Skipped:       {}", self.skipped);
+        log::info!(target: logging::SUMMARY, "  Not attempted: {}", self.not_attempted());
+    }
+}
+
+/// Number of files skipped due to duration thresholds.
+#[derive(Default)]
+struct DurationSkips {
+    too_short: usize,
+    too_long: usize,
+}
+
+// Cue sheet whose tracks are already in DB, but whose hash has either changed or
+// has not yet been stored.
+struct CueSheet {
+    file: String,
+    hash: String,
+    changed: bool,
+}
+
+/// Build set of exclude patterns. Patterns without a '/' are matched against the
+/// file, or folder, name - so '*.live.flac' matches in any folder.
+pub fn build_exclude(patterns: &Vec<String>) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let full = if pattern.contains('/') { pattern.clone() } else { format!("**/{}", pattern) };
+        match GlobBuilder::new(&full).literal_separator(true).build() {
+            Ok(glob) => { builder.add(glob); }
+            Err(e) => { return Err(Error::Config(format!("Invalid exclude pattern ({}). {}", pattern, e))); }
+        }
+    }
+    match builder.build() {
+        Ok(set) => Ok(set),
+        Err(e) => Err(Error::Config(format!("Invalid exclude patterns. {}", e))),
+    }
+}
+
+/// Read exclude patterns from file, one per line. Empty lines, and those starting with
+/// '#', are ignored.
+pub fn read_exclude_file(path: &str) -> Result<Vec<String>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => { return Err(Error::Config(format!("Failed to open exclude file ({}). {}", path, e))); }
+    };
+    let mut patterns: Vec<String> = Vec::new();
+    for line in BufReader::new(file).lines().flatten() {
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            patterns.push(line.to_string());
+        }
+    }
+    Ok(patterns)
+}
+
+/// Build list of extensions to analyse. 'ext', 'extra', and 'exclude' are comma separated
+/// lists, if 'ext' is empty then the default list is used. 'extra' extends this list, and
+/// as these have been explicitly requested unknown extensions only cause a warning.
+pub fn build_extensions(ext: &str, extra: &str, exclude: &str) -> Result<Vec<String>, Error> {
+    let split = |val: &str| -> Vec<String> {
+        val.split(',').map(|e| e.trim().trim_start_matches('.').to_string()).filter(|e| !e.is_empty()).collect()
+    };
+    let mut extensions = split(ext);
+    if extensions.is_empty() {
+        extensions = DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect();
+    }
+    for e in &extensions {
+        if !DECODABLE_EXTENSIONS.contains(&e.to_lowercase().as_str()) {
+            return Err(Error::Config(format!("Unsupported extension ({}), supported: {}", e, DECODABLE_EXTENSIONS.join(", "))));
+        }
+    }
+    for e in split(extra) {
+        if !DECODABLE_EXTENSIONS.contains(&e.to_lowercase().as_str()) {
+            log::warn!("Extension ({}) may not be supported by ffmpeg, files that fail to decode will be listed as failures", e);
+        }
+        if !extensions.contains(&e) {
+            extensions.push(e);
+        }
+    }
+    let exclude = split(exclude);
+    extensions.retain(|e| !exclude.contains(e));
+    if extensions.is_empty() {
+        return Err(Error::Config("All extensions have been excluded".to_string()));
+    }
+    Ok(extensions)
+}
+
+fn valid_extension(opts: &Options, ext: &str) -> bool {
+    opts.extensions.iter().any(|e| e == ext)
+}
+
+// Return name of first skip marker file found in folder
+fn skip_marker<'a>(opts: &'a Options, dir: &Path) -> Option<&'a str> {
+    opts.skip_markers.iter().find(|m| dir.join(m).exists()).map(|m| m.as_str())
+}
+
+// Indexes of exclude patterns matching path, relative to music folder
+fn exclude_matches(opts: &Options, mpath: &Path, path: &Path) -> Vec<usize> {
+    if opts.exclude.is_empty() {
+        return Vec::new();
+    }
+    match path.strip_prefix(mpath) {
+        Ok(stripped) => {
+            let mut sname = String::from(stripped.to_string_lossy());
+            if cfg!(windows) {
+                sname = sname.replace("\\", "/");
+            }
+            opts.exclude.matches(&sname)
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+// Check if path, relative to music folder, matches an exclude pattern
+fn excluded(opts: &Options, mpath: &Path, path: &Path) -> bool {
+    !exclude_matches(opts, mpath, path).is_empty()
+}
+
+// macOS writes metadata for files to '._' (AppleDouble) files on file systems that do
+// not support resource forks. These have the same extension as the audio file, but are
+// not audio.
+fn is_apple_double(name: &str) -> bool {
+    name.starts_with("._")
+}
+
+// Reason to skip hidden files and folders, and well-known system folders
+fn hidden_or_junk(opts: &Options, name: &str, is_dir: bool) -> Option<&'static str> {
+    if !is_dir && is_apple_double(name) {
+        Some("macOS resource fork")
+    } else if is_dir && JUNK_FOLDERS.contains(&name) {
+        Some("system folder")
+    } else if !opts.include_hidden && name.starts_with('.') {
+        Some("hidden")
+    } else {
+        None
+    }
+}
+
+// Check whether any part of path, relative to music folder, is hidden or a system folder
+fn in_hidden_or_junk(opts: &Options, mpath: &Path, path: &Path) -> bool {
+    match path.strip_prefix(mpath) {
+        Ok(stripped) => {
+            let count = stripped.components().count();
+            stripped.components().enumerate().any(|(index, c)| {
+                hidden_or_junk(opts, &c.as_os_str().to_string_lossy(), index + 1 < count || path.is_dir()).is_some()
+            })
+        }
+        Err(_) => false,
+    }
+}
+
+// Audio file found when scanning a music folder
+struct FoundFile {
+    path: PathBuf,
+    has_cue: bool,
+    // Found via a symlink, i.e. file is a link or is within a linked folder
+    linked: bool,
+}
+
+// Settings, and state, shared by threads scanning a music folder
+struct ScanContext<'a> {
+    mpath: &'a Path,
+    // Canonical path of music folder, used to detect links to folders within it
+    root: PathBuf,
+    earlier: &'a [PathBuf],
+    opts: &'a Options,
+    // Canonical paths of linked folders that have been scanned
+    visited: Mutex<HashSet<PathBuf>>,
+    // Number of files, and folders, matched by each exclude pattern
+    exclude_counts: Mutex<Vec<usize>>,
+    // Spinner showing folder being scanned, and number of files found so far
+    spinner: ProgressBar,
+    num_found: AtomicUsize,
+    cache: Option<ScanCache>,
+    // Files last modified before this are ignored
+    modified_after: Option<SystemTime>,
+}
+
+// Check whether a symlinked folder should be scanned. Links to folders within the music
+// folder are not, as these are scanned anyway (and could cause a loop), and each folder
+// outside of it is only scanned once.
+fn scan_linked_dir(ctx: &ScanContext, pb: &Path) -> bool {
+    if !ctx.opts.follow_symlinks {
+        log::debug!("Skipping '{}', is a symlink", pb.to_string_lossy());
+        return false;
+    }
+    let target = match pb.canonicalize() {
+        Ok(target) => target,
+        Err(_) => { return false; }
+    };
+    if target.starts_with(&ctx.root) {
+        log::debug!("Skipping '{}', links to folder within music folder", pb.to_string_lossy());
+        return false;
+    }
+    if !ctx.visited.lock().unwrap().insert(target) {
+        log::debug!("Skipping '{}', linked folder already scanned", pb.to_string_lossy());
+        return false;
+    }
+    true
+}
+
+fn modified_since(path: &Path, after: SystemTime) -> bool {
+    match path.metadata().and_then(|m| m.modified()) {
+        Ok(modified) => modified >= after,
+        // If modification time cannot be read, then do not skip file
+        Err(_) => true,
+    }
+}
+
+// Paths are stored in DB, and passed to decoder, as strings - so a path that is not
+// valid UTF-8 would be altered, and then would not match on the next scan.
+fn valid_utf8(path: &Path) -> bool {
+    if path.to_str().is_some() {
+        return true;
+    }
+    log::warn!("Skipping '{}', path is not valid UTF-8", path.to_string_lossy());
+    false
+}
+
+// Read a single folder, adding sub-folders to be scanned to 'folders' and audio files
+// to 'files'. 'linked' is set if folder was reached via a symlink.
+fn scan_dir(ctx: &ScanContext, dir: &Path, linked: bool, folders: &mut Vec<(PathBuf, bool)>, files: &mut Vec<FoundFile>, skipped: &mut usize) {
+    let (mpath, opts) = (ctx.mpath, ctx.opts);
+    let listing = match &ctx.cache {
+        Some(cache) => cache.read_dir(dir),
+        None => scancache::read_dir(dir),
+    };
+    let entries = match listing {
+        Some(entries) => entries,
+        None => { return; }
+    };
+    // Use folder listing to check for CUE files, rather than checking each file. Windows
+    // and macOS file systems are usually case-insensitive.
+    let key = |name: &OsStr| -> String {
+        if cfg!(any(windows, target_os = "macos")) { name.to_string_lossy().to_lowercase() } else { name.to_string_lossy().to_string() }
+    };
+    let names: HashSet<String> = entries.iter().filter_map(|e| e.path.file_name()).map(key).collect();
+    ctx.spinner.set_message(format!("{} file(s) found, scanning {}", ctx.num_found.load(Ordering::Relaxed), dir.to_string_lossy()));
+    for entry in entries {
+        let (pb, is_dir) = (entry.path, entry.is_dir);
+        if let Some(reason) = hidden_or_junk(opts, &pb.file_name().unwrap_or_default().to_string_lossy(), is_dir) {
+            log::debug!("Skipping '{}', {}", pb.to_string_lossy(), reason);
+            continue;
+        }
+        let matches = exclude_matches(opts, mpath, &pb);
+        if !matches.is_empty() {
+            if opts.dry_run {
+                report::skipped(&pb.to_string_lossy(), &format!("matches exclude pattern '{}'", opts.exclude_patterns[matches[0]]));
+                let mut counts = ctx.exclude_counts.lock().unwrap();
+                for index in matches {
+                    counts[index] += 1;
+                }
+            }
+            if is_dir {
+                log::info!("Skipping '{}', matches exclude pattern", pb.to_string_lossy());
+            } else {
+                log::debug!("Skipping '{}', matches exclude pattern", pb.to_string_lossy());
+            }
+            continue;
+        }
+        if is_dir {
+            if let Some(marker) = skip_marker(opts, &pb) {
+                log::info!("Skipping '{}', found '{}'", pb.to_string_lossy(), marker);
+                report::skipped(&pb.to_string_lossy(), &format!("found '{}'", marker));
+            } else if !entry.is_symlink {
+                folders.push((pb, linked));
+            } else if scan_linked_dir(ctx, &pb) {
+                folders.push((pb, true));
+            }
+        } else if entry.is_file {
+            let is_link = entry.is_symlink;
+            if is_link && !opts.follow_symlinks {
+                log::debug!("Skipping '{}', is a symlink", pb.to_string_lossy());
+                continue;
+            }
+            if_chain! {
+                if let Some(ext) = pb.extension();
+                if valid_extension(opts, &ext.to_string_lossy());
+                if let Ok(stripped) = pb.strip_prefix(mpath);
+                then {
+                    if in_earlier_music_folder(&pb, stripped, ctx.earlier) || too_small(&pb) || !valid_utf8(&pb) {
+                        *skipped += 1;
+                        continue;
+                    }
+                    let has_cue = pb.with_extension("cue").file_name().map_or(false, |n| names.contains(&key(n)));
+                    if let Some(after) = ctx.modified_after {
+                        // For CUE files, editing either the audio or the CUE file counts
+                        if !modified_since(&pb, after) && !(has_cue && modified_since(&pb.with_extension("cue"), after)) {
+                            log::trace!("Skipping '{}', not modified recently", pb.to_string_lossy());
+                            continue;
+                        }
+                    }
+                    files.push(FoundFile { path: pb, has_cue: has_cue, linked: linked || is_link });
+                    ctx.num_found.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+// Scan folder, and its sub-folders, using multiple threads - as on network file systems
+// each folder read and file check may be slow. Files are returned sorted, so that the
+// results are the same each run.
+fn scan_folder(mpath: &Path, path: &Path, earlier: &[PathBuf], skipped: &mut usize, opts: &Options) -> Vec<FoundFile> {
+    let threads = if opts.max_threads > 0 { opts.max_threads } else { num_cpus::get() };
+    let ctx = ScanContext {
+        mpath: mpath,
+        root: mpath.canonicalize().unwrap_or(mpath.to_path_buf()),
+        earlier: earlier,
+        opts: opts,
+        visited: Mutex::new(HashSet::new()),
+        exclude_counts: Mutex::new(vec![0; opts.exclude_patterns.len()]),
+        spinner: if opts.show_progress && !opts.progress_json {
+            ProgressBar::new_spinner().with_style(ProgressStyle::default_spinner().template("{spinner} {wide_msg}"))
+        } else {
+            ProgressBar::hidden()
+        },
+        num_found: AtomicUsize::new(0),
+        cache: if opts.scan_cache.is_empty() { None } else { Some(ScanCache::load(&opts.scan_cache)) },
+        modified_after: if opts.since > 0 { SystemTime::now().checked_sub(Duration::from_secs(opts.since)) } else { None },
+    };
+    ctx.spinner.enable_steady_tick(100);
+    // Folders still to be read, and number of folders currently being read
+    let pending: Mutex<(Vec<(PathBuf, bool)>, usize)> = Mutex::new((vec![(path.to_path_buf(), false)], 0));
+    let found: Mutex<(Vec<FoundFile>, usize)> = Mutex::new((Vec::new(), 0));
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                let mut folders: Vec<(PathBuf, bool)> = Vec::new();
+                let mut files: Vec<FoundFile> = Vec::new();
+                let mut num_skipped: usize = 0;
+                loop {
+                    if terminated() {
+                        break;
+                    }
+                    let next = {
+                        let mut pending = pending.lock().unwrap();
+                        match pending.0.pop() {
+                            Some(dir) => {
+                                pending.1 += 1;
+                                Some(dir)
+                            }
+                            None if pending.1 == 0 => { break; }
+                            None => None,
+                        }
+                    };
+                    match next {
+                        Some((dir, linked)) => {
+                            scan_dir(&ctx, &dir, linked, &mut folders, &mut files, &mut num_skipped);
+                            let mut pending = pending.lock().unwrap();
+                            pending.0.append(&mut folders);
+                            pending.1 -= 1;
+                        }
+                        None => { thread::sleep(Duration::from_millis(10)); }
+                    }
+                }
+                let mut found = found.lock().unwrap();
+                found.0.append(&mut files);
+                found.1 += num_skipped;
+            });
+        }
+    });
+    ctx.spinner.finish_and_clear();
+    // A partial scan would remove listings of folders not reached
+    if let Some(cache) = &ctx.cache {
+        if !opts.dry_run && !terminated() {
+            cache.save(path);
+        }
+    }
+    let (mut files, num_skipped) = found.into_inner().unwrap();
+    *skipped += num_skipped;
+    if opts.dry_run {
+        // Show how often each pattern matched, so that mistyped patterns can be spotted
+        let counts = ctx.exclude_counts.lock().unwrap();
+        for (pattern, count) in opts.exclude_patterns.iter().zip(counts.iter()) {
+            if *count == 0 {
+                log::warn!("Exclude pattern '{}' did not match any files or folders", pattern);
+            } else {
+                log::info!("Exclude pattern '{}' matched {} file(s)/folder(s)", pattern, count);
+            }
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    remove_linked_duplicates(&ctx, files, skipped)
+}
+
+// Where links cause the same file to be found more than once, only keep the first. Files
+// not found via a link are preferred.
+fn remove_linked_duplicates(ctx: &ScanContext, files: Vec<FoundFile>, skipped: &mut usize) -> Vec<FoundFile> {
+    if !files.iter().any(|f| f.linked) {
+        return files;
+    }
+    let mut seen: HashSet<PathBuf> = files.iter()
+        .filter(|f| !f.linked)
+        .filter_map(|f| f.path.strip_prefix(ctx.mpath).ok())
+        .map(|stripped| ctx.root.join(stripped))
+        .collect();
+    files.into_iter().filter(|f| {
+        if !f.linked {
+            return true;
+        }
+        let target = f.path.canonicalize().unwrap_or(f.path.clone());
+        if seen.insert(target) {
+            true
+        } else {
+            log::debug!("Skipping '{}', linked file already found", f.path.to_string_lossy());
+            *skipped += 1;
+            false
+        }
+    }).collect()
+}
+
+fn get_file_list(db: &mut db::Db, mpath: &Path, path: &Path, track_paths: &mut Vec<String>, cue_sheets: &mut Vec<CueSheet>, earlier: &[PathBuf], skipped: &mut usize, durations: &mut DurationSkips, opts: &Options) {
     if !path.is_dir() {
         return;
     }
 
-    if let Ok(items) = path.read_dir() {
-        for item in items {
-            if let Ok(entry) = item {
-                check_dir_entry(db, mpath, entry, track_paths);
+    let files = scan_folder(mpath, path, earlier, skipped, opts);
+    let known = KnownPaths::new(db, opts);
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for file in files {
+        check_file(db, &known, mpath, file, track_paths, cue_sheets, &mut candidates, skipped, opts);
+    }
+
+    // Tags of new files are read in parallel, as for large collections (especially on
+    // network mounts) reading these one at a time can take longer than the analysis.
+    let candidate_tags = read_candidate_tags(&candidates, opts);
+    for (candidate, ctags) in candidates.into_iter().zip(candidate_tags) {
+        let embedded = ctags.cue.and_then(|cue| {
+            log::debug!("Found embedded cue sheet in '{}'", candidate.path.to_string_lossy());
+            cue::write_temp(&cue, &candidate.path, track_paths.len())
+        });
+        match embedded {
+            Some(cue_path) => { track_paths.push(String::from(cue_path.to_string_lossy())); }
+            None => {
+                if outside_duration(db, &candidate.path, &candidate.sname, ctags.duration, opts, durations) {
+                    *skipped += 1;
+                } else {
+                    track_paths.push(String::from(candidate.path.to_string_lossy()));
+                }
+            }
+        }
+    }
+}
+
+// New file that is to be analysed, unless its tags show otherwise
+struct Candidate {
+    path: PathBuf,
+    sname: String,
+}
+
+struct CandidateTags {
+    cue: Option<String>,
+    // Only read if a duration range has been configured
+    duration: Option<u32>,
+}
+
+// Read tags of candidate files using multiple threads. Results are in the same order
+// as 'candidates'.
+fn read_candidate_tags(candidates: &[Candidate], opts: &Options) -> Vec<CandidateTags> {
+    let threads = if opts.max_threads > 0 { opts.max_threads } else { num_cpus::get() };
+    let check_duration = opts.min_duration > 0 || opts.max_duration > 0;
+    let chunk_size = ((candidates.len() + threads - 1) / threads).max(1);
+    thread::scope(|s| {
+        let handles: Vec<_> = candidates
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk.iter().map(|candidate| {
+                        let cue = embedded_cue(&candidate.path);
+                        let duration = if check_duration && cue.is_none() { tags::duration(&candidate.path) } else { None };
+                        CandidateTags { cue: cue, duration: duration }
+                    }).collect::<Vec<CandidateTags>>()
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    })
+}
+
+// Paths read from DB once per scan, rather than querying DB for each file
+struct KnownPaths {
+    tracks: HashSet<String>,
+    failures: HashSet<String>,
+    // Tracks analysed with an older features version, only read if these are to be
+    // re-analysed
+    stale: HashSet<String>,
+}
+
+impl KnownPaths {
+    fn new(db: &db::Db, opts: &Options) -> Self {
+        Self {
+            tracks: db.get_all_paths(),
+            failures: db.get_failures().into_iter().map(|(file, _)| file).collect(),
+            stale: if opts.refresh_stale { db.get_stale_paths() } else { HashSet::new() },
+        }
+    }
+
+    fn track(&self, sname: &str) -> bool {
+        self.tracks.contains(&db::normalise(sname))
+    }
+
+    fn failure(&self, sname: &str) -> bool {
+        self.failures.contains(&db::normalise(sname))
+    }
+
+    fn stale(&self, sname: &str) -> bool {
+        self.stale.contains(&db::normalise(sname))
+    }
+
+    fn cue_track(&self, mpath: &Path, pb: &PathBuf) -> bool {
+        first_cue_track(mpath, pb).map_or(false, |t| self.track(&t))
+    }
+
+    fn stale_cue_track(&self, mpath: &Path, pb: &PathBuf) -> bool {
+        first_cue_track(mpath, pb).map_or(false, |t| self.stale(&t))
+    }
+}
+
+// Check whether file found by scan needs to be analysed. New files, whose tags need to
+// be read before deciding, are added to 'candidates'.
+fn check_file(db: &db::Db, known: &KnownPaths, mpath: &Path, file: FoundFile, track_paths: &mut Vec<String>, cue_sheets: &mut Vec<CueSheet>, candidates: &mut Vec<Candidate>, skipped: &mut usize, opts: &Options) {
+    let pb = file.path;
+    let stripped = match pb.strip_prefix(mpath) {
+        Ok(stripped) => stripped,
+        Err(_) => { return; }
+    };
+    let sname = String::from(stripped.to_string_lossy());
+    let ext = pb.extension().unwrap_or_default().to_string_lossy();
+    let cue_file = pb.with_extension("cue");
+    // Don't retry files that previously failed, unless asked to
+    if known.failure(&sname) || (file.has_cue && known.failure(&stripped.with_extension("cue").to_string_lossy())) {
+        log::debug!("Skipping '{}', previously failed", sname);
+        report::skipped(&pb.to_string_lossy(), "previously failed");
+        *skipped += 1;
+        return;
+    }
+    if file.has_cue {
+        // For cue files, check if first track is in DB, and if so whether
+        // the cue file has since been edited.
+        if opts.force || !known.cue_track(mpath, &pb) || known.stale_cue_track(mpath, &pb) {
+            track_paths.push(cue_path_to_analyse(&cue_file, track_paths.len()));
+        } else if let Some(hash) = cue::hash(&cue_file) {
+            match db.get_cue_hash(&sname) {
+                Some(stored) => {
+                    if stored != hash {
+                        cue_sheets.push(CueSheet { file: sname, hash: hash, changed: true });
+                        track_paths.push(cue_path_to_analyse(&cue_file, track_paths.len()));
+                    }
+                }
+                None => {
+                    cue_sheets.push(CueSheet { file: sname, hash: hash, changed: false });
+                }
+            }
+        }
+    } else {
+        let in_db = known.track(&sname);
+        let is_flac = ext.eq_ignore_ascii_case("flac");
+        let stale = if in_db { known.stale(&sname) } else { is_flac && known.stale_cue_track(mpath, &pb) };
+        if opts.force || stale || (!in_db && !(is_flac && known.cue_track(mpath, &pb))) {
+            candidates.push(Candidate { path: pb, sname: sname });
+        }
+    }
+}
+
+// Tracks are stored relative to their music folder, so the same relative path within
+// two music folders would refer to the same DB entry. Only the first is analysed.
+fn in_earlier_music_folder(pb: &Path, stripped: &Path, earlier: &[PathBuf]) -> bool {
+    for mpath in earlier {
+        let other = mpath.join(stripped);
+        if other.is_file() {
+            log::warn!("Skipping '{}', has same relative path as '{}'", pb.to_string_lossy(), other.to_string_lossy());
+            return true;
+        }
+    }
+    false
+}
+
+// Check if duration of file is outside of the configured range. If so, and this is not
+// a dry-run, then file is added to DB as ignored - so that it is not seen as new when
+// next scanned.
+fn outside_duration(db: &db::Db, pb: &Path, sname: &String, duration: Option<u32>, opts: &Options, durations: &mut DurationSkips) -> bool {
+    if opts.min_duration == 0 && opts.max_duration == 0 {
+        return false;
+    }
+    let duration = match duration {
+        Some(duration) => duration,
+        None => { return false; }
+    };
+    let reason = if opts.min_duration > 0 && duration < opts.min_duration {
+        durations.too_short += 1;
+        format!("shorter than {} seconds", opts.min_duration)
+    } else if opts.max_duration > 0 && duration > opts.max_duration {
+        durations.too_long += 1;
+        format!("longer than {} seconds", opts.max_duration)
+    } else {
+        return false;
+    };
+    log::debug!("Skipping '{}', {}", sname, reason);
+    report::skipped(&pb.to_string_lossy(), &reason);
+    if !opts.dry_run {
+        let mut meta = tags::read(&String::from(pb.to_string_lossy()));
+        tags::title_if_empty(&mut meta, sname);
+        meta.duration = duration;
+        db.add_unanalysed(sname, &meta);
+    }
+    true
+}
+
+// Skip zero-length, or truncated, files rather than having decoder fail on these
+fn too_small(pb: &Path) -> bool {
+    match pb.metadata() {
+        Ok(meta) => {
+            if meta.len() < MIN_FILE_SIZE {
+                log::warn!("Skipping '{}', file is too small ({} bytes)", pb.to_string_lossy(), meta.len());
+                return true;
+            }
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+// Non UTF-8 cue files, and those with pregaps, are analysed via a temporary copy.
+fn cue_path_to_analyse(cue_file: &PathBuf, index: usize) -> String {
+    match cue::prepare(cue_file, index) {
+        Some(path) => String::from(path.to_string_lossy()),
+        None => String::from(cue_file.to_string_lossy()),
+    }
+}
+
+// File size is used as a cheap estimate of duration. For CUE files this is the size
+// of the audio they reference.
+fn file_size(path: &str) -> u64 {
+    let pb = Path::new(path);
+    if path.ends_with(".cue") {
+        let size = cue::audio_size(pb);
+        if size > 0 {
+            return size;
+        }
+    }
+    fs::metadata(pb).map(|m| m.len()).unwrap_or(0)
+}
+
+// Sort files to be analysed, ties are sorted by path
+fn sort_track_paths(track_paths: &mut Vec<String>, order: Order) {
+    track_paths.sort();
+    match order {
+        Order::Path => { }
+        Order::Shortest => { track_paths.sort_by_cached_key(|p| file_size(p)); }
+        Order::Largest => { track_paths.sort_by_cached_key(|p| Reverse(file_size(p))); }
+        Order::Random => {
+            // RandomState is randomly seeded, so hashes give a different order each run
+            let state = RandomState::new();
+            track_paths.sort_by_cached_key(|p| {
+                let mut hasher = state.build_hasher();
+                p.hash(&mut hasher);
+                hasher.finish()
+            });
+        }
+    }
+}
+
+// DB name of first track of a CUE file
+fn first_cue_track(mpath: &Path, pb: &PathBuf) -> Option<String> {
+    let mut cue_track_path = pb.clone();
+    let ext = pb.extension().unwrap_or_default().to_string_lossy();
+    cue_track_path.set_extension(format!("{}{}1", ext, db::CUE_MARKER));
+    match cue_track_path.strip_prefix(mpath) {
+        Ok(cue_track_stripped) => Some(String::from(cue_track_stripped.to_string_lossy())),
+        Err(_) => None,
+    }
+}
+
+fn cue_track_in_db(db: &db::Db, mpath: &Path, pb: &PathBuf) -> bool {
+    if let Some(cue_track_sname) = first_cue_track(mpath, pb) {
+        if let Ok(id) = db.get_rowid(&cue_track_sname) {
+            return id>0;
+        }
+    }
+    false
+}
+
+// If FLAC file has an embedded cue sheet then write this to a temporary file and
+// return its path.
+fn check_embedded_cue(pb: &PathBuf, index: usize) -> Option<PathBuf> {
+    let cue = embedded_cue(pb)?;
+    log::debug!("Found embedded cue sheet in '{}'", pb.to_string_lossy());
+    cue::write_temp(&cue, pb, index)
+}
+
+fn embedded_cue(pb: &Path) -> Option<String> {
+    let ext = pb.extension()?.to_string_lossy();
+    if !ext.eq_ignore_ascii_case("flac") {
+        return None;
+    }
+    cue::read_embedded(pb)
+}
+
+// Show (up to max, 0 for all) errors on screen, but write complete list to log file.
+// Errors are a list of file and error message.
+fn show_error_list(title: &str, errors: &Vec<(String, String)>, max: usize) {
+    if errors.is_empty() {
+        return;
+    }
+    let max = if max == 0 { errors.len() } else { max };
+    log::error!("{}", title);
+    for (index, (file, err)) in errors.iter().enumerate() {
+        logging::file_error(log::Level::Error, file, err, index < max);
+    }
+    if errors.len() > max {
+        log::error!("  + {} other(s)", errors.len() - max);
+    }
+}
+
+fn show_errors(failed: &Vec<(String, String)>, tag_error: &Vec<(String, String)>, opts: &Options) {
+    show_error_list("Failed to analyse the following file(s):", failed, opts.max_errors);
+    show_error_list("Failed to read tags of the following file(s):", tag_error, opts.max_tag_errors);
+}
+
+// Write complete list of failures, one per line, to errors file
+fn write_errors_file(errors_file: &str, errors: &Vec<(String, String)>) {
+    let mut contents = String::new();
+    for (path, err) in errors {
+        contents.push_str(&format!("{}\t{}\n", path, err));
+    }
+    match fs::write(errors_file, contents) {
+        Ok(_) => { log::info!("Wrote {} failure(s) to '{}'", errors.len(), errors_file); }
+        Err(e) => { log::error!("Failed to write errors file ({}). {}", errors_file, e); }
+    }
+}
+
+// Path relative to music folder. Decoder may return a canonical path (e.g. if music
+// folder is, or contains, a symlink) so if path is not within music folder compare the
+// canonical forms.
+fn relative_path(mpath: &Path, path: &Path) -> Option<PathBuf> {
+    if let Ok(stripped) = path.strip_prefix(mpath) {
+        return Some(stripped.to_path_buf());
+    }
+    let cmpath = mpath.canonicalize().ok()?;
+    let cpath = path.canonicalize().ok()?;
+    cpath.strip_prefix(&cmpath).ok().map(|p| p.to_path_buf())
+}
+
+/// Analysis rate, calculated over the most recently analysed files - so that a single
+/// large file, or a slow start, does not skew the estimate.
+struct Rate {
+    times: VecDeque<Instant>,
+}
+
+impl Rate {
+    fn new() -> Self {
+        Self { times: VecDeque::from(vec![Instant::now()]) }
+    }
+
+    fn inc(&mut self) {
+        self.times.push_back(Instant::now());
+        if self.times.len() > RATE_WINDOW + 1 {
+            self.times.pop_front();
+        }
+    }
+
+    fn per_minute(&self) -> Option<f64> {
+        if self.times.len() < 2 {
+            return None;
+        }
+        let secs = self.times.back()?.duration_since(*self.times.front()?).as_secs_f64();
+        if secs <= 0.0 {
+            return None;
+        }
+        Some((self.times.len() - 1) as f64 * 60.0 / secs)
+    }
+
+    /// Estimated seconds to analyse 'remaining' files.
+    fn eta(&self, remaining: u64) -> Option<u64> {
+        self.per_minute().map(|rate| (remaining as f64 * 60.0 / rate) as u64)
+    }
+}
+
+/// Time taken to analyse each file. The decoder splits the files into chunks, and a
+/// thread analyses each chunk in order - so the time taken for a file is that since
+/// the previous result from the same chunk.
+struct FileTimes {
+    chunks: HashMap<String, usize>,
+    last: Vec<Instant>,
+    times: Vec<(String, Duration)>,
+}
+
+impl FileTimes {
+    fn new(paths: &[String], threads: usize) -> Self {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get()).min(threads);
+        let chunk_len = decoder_chunk_len(paths.len(), cores);
+        Self {
+            chunks: paths.iter().enumerate().map(|(index, path)| (path.clone(), index / chunk_len)).collect(),
+            last: vec![Instant::now(); (paths.len() + chunk_len - 1) / chunk_len],
+            times: Vec::new(),
+        }
+    }
+
+    // Record time of file, CUE files produce a result per track - so only the first is used
+    fn done(&mut self, path: &str, sname: &str) {
+        if let Some(chunk) = self.chunks.remove(path) {
+            let now = Instant::now();
+            let taken = now.duration_since(self.last[chunk]);
+            self.last[chunk] = now;
+            log::debug!("Analysed '{}' in {:.1}s", sname, taken.as_secs_f64());
+            self.times.push((sname.to_string(), taken));
+        }
+    }
+
+    fn show_slowest(&mut self) {
+        if self.times.len() < 2 {
+            return;
+        }
+        self.times.sort_by(|a, b| b.1.cmp(&a.1));
+        log::info!("Slowest file(s) to analyse:");
+        for (sname, taken) in self.times.iter().take(NUM_SLOWEST) {
+            log::info!("  {:.1}s {}", taken.as_secs_f64(), sname);
+        }
+    }
+}
+
+// Number of files per chunk, as calculated by bliss-audio 0.9.3's
+// analyze_paths_with_cores() - where 'cores' is the number of threads requested, limited
+// to the available parallelism. If there are fewer files than cores, all files are in
+// one chunk. This must be kept in sync with bliss-audio.
+fn decoder_chunk_len(num_paths: usize, cores: usize) -> usize {
+    match num_paths / cores.max(1) {
+        0 => num_paths.max(1),
+        len => len,
+    }
+}
+
+// Duration of CUE track to store, in seconds. bliss slices the final track up to the
+// end of the audio, so no adjustment is required. None if track has no audio.
+fn cue_track_duration(duration: Duration) -> Option<u32> {
+    if duration.is_zero() {
+        None
+    } else {
+        // Round, so that tracks under a second are not treated as having no duration
+        Some((duration.as_secs_f64().round() as u32).max(1))
+    }
+}
+
+fn format_eta(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, (secs / 60) % 60, secs % 60)
+}
+
+pub fn analyse_new_files(db: &db::Db, mpath: &PathBuf, track_paths: Vec<String>, opts: &Options, notifier: &mut notify::Notifier, status: &mut status::StatusFile, summary: &mut Summary) -> Result<()> {
+    let total = track_paths.len();
+    // When not showing progress bar (e.g. output redirected to a file), periodically log progress instead
+    let progress = if opts.show_progress && !opts.progress_json {
+        ProgressBar::new(total.try_into().unwrap()).with_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{bar:25}] {percent:>3}% {pos:>6}/{len:6} ETA {eta_precise} {wide_msg}")
+                .progress_chars("=> "),
+        )
+    } else {
+        ProgressBar::hidden()
+    };
+    let mut last_progress_log = Instant::now();
+    let mut rate = Rate::new();
+    let cpu_threads: NonZeroUsize = match opts.max_threads {
+        0 => NonZeroUsize::new(num_cpus::get()).unwrap(),
+        _ => NonZeroUsize::new(opts.max_threads).unwrap(),
+    };
+
+    let mut analysed = 0;
+    let mut consecutive_failures: usize = 0;
+    let mut failed: Vec<(String, String)> = Vec::new();
+    let mut tag_error: Vec<(String, String)> = Vec::new();
+    let mut reported_cue:HashSet<String> = HashSet::new();
+    let mut cue_years:HashMap<PathBuf, Option<u32>> = HashMap::new();
+    let mut cue_files:HashSet<String> = track_paths.iter().filter(|p| p.ends_with(".cue")).cloned().collect();
+    let mut file_times = FileTimes::new(&track_paths, cpu_threads.get());
+
+    log::info!("Analysing new files");
+    for (path, result) in <FFmpeg as Decoder>::analyze_paths_with_cores(track_paths, cpu_threads) {
+        cue_files.remove(&String::from(path.to_string_lossy()));
+        // Embedded and rewritten cue sheets are analysed from a temporary file, so
+        // path may not be within music folder.
+        let sname = match relative_path(mpath, &path) {
+            Some(stripped) => String::from(stripped.to_string_lossy()),
+            None => String::from(path.to_string_lossy()),
+        };
+        file_times.done(&path.to_string_lossy(), &sname);
+        progress.set_message(match rate.per_minute() {
+            Some(per_minute) => format!("{:.0} files/min {}", per_minute, sname),
+            None => sname.clone(),
+        });
+        let mut inc_progress = true; // Only want to increment progress once for cue tracks
+        let num_failed = failed.len();
+        match result {
+            Ok(track) => {
+                let cpath = String::from(path.to_string_lossy());
+                match track.cue_info {
+                    Some(cue) => {
+                        match (track.track_number, relative_path(mpath, &cue.audio_file_path)) {
+                            (Some(track_num), Some(stripped)) => {
+                                let sname = String::from(stripped.to_string_lossy());
+
+                                if reported_cue.contains(&cpath) {
+                                    inc_progress = false;
+                                } else {
+                                    analysed += 1;
+                                    reported_cue.insert(cpath);
+                                    // Store hash of cue file, so that edits can be detected. Embedded
+                                    // and rewritten cue sheets are read from a temporary file, so
+                                    // ignore these.
+                                    if cue.cue_path.starts_with(mpath) {
+                                        if let Some(hash) = cue::hash(&cue.cue_path) {
+                                            db.set_cue_hash(&sname, &hash);
+                                        }
+                                    }
+                                }
+                                let db_path = format!("{}{}{}", sname, db::CUE_MARKER, track_num);
+                                if track_num == cue::PREGAP_TRACK {
+                                    // Pregap split from following track, see cue::prepare
+                                    log::trace!("Skipping pregap of '{}'", sname);
+                                } else if let Some(duration) = cue_track_duration(track.duration) {
+                                    let meta = db::Metadata {
+                                        title: track.title.unwrap_or_default().to_string(),
+                                        artist: track.artist.unwrap_or_default().to_string(),
+                                        album: track.album.unwrap_or_default().to_string(),
+                                        album_artist: track.album_artist.unwrap_or_default().to_string(),
+                                        genre: track.genre.unwrap_or_default().to_string(),
+                                        duration: duration,
+                                        track_num: u32::try_from(track_num).ok(),
+                                        disc: track.disc_number.and_then(|d| u32::try_from(d).ok()),
+                                        year: *cue_years.entry(cue.cue_path.clone()).or_insert_with(|| cue::year(&cue.cue_path)),
+                                        // Moved files are not looked for via size for CUE tracks
+                                        size: None,
+                                    };
+                                    db.add_track(&db_path, &meta, &track.analysis);
+                                } else {
+                                    log::warn!("Skipping '{}', track has no duration", db_path);
+                                }
+                            }
+                            (Some(_), None) => {
+                                // Cannot store path relative to music folder, so do not add to DB
+                                if reported_cue.insert(cpath.clone()) {
+                                    log::warn!("Audio file of '{}' is not within music folder", cpath);
+                                    failed.push((sname.clone(), String::from("Audio file is not within music folder")));
+                                    summary.errors.push((String::from(cue.audio_file_path.to_string_lossy()), String::from("Audio file is not within music folder")));
+                                } else {
+                                    inc_progress = false;
+                                }
+                            }
+                            (None, _) => {
+                                failed.push((sname.clone(), String::from("No track number?")));
+                                db.add_failure(&sname, "No track number?");
+                                summary.errors.push((String::from(cue.audio_file_path.to_string_lossy()), String::from("No track number?")));
+                            }
+                        }
+                    }
+                    None if track.duration.as_millis() == 0 => {
+                        // Decoder produced no samples, so analysis is meaningless
+                        failed.push((sname.clone(), String::from("No audio decoded")));
+                        db.add_failure(&sname, "No audio decoded");
+                        summary.errors.push((cpath.clone(), String::from("No audio decoded")));
+                    }
+                    None => {
+                        // Use lofty to read tags here, and not bliss's, so that if update
+                        // tags is ever used they are from the same source.
+                        let mut meta = tags::read(&cpath);
+                        if meta.is_empty() {
+                            // Lofty failed? Try from bliss...
+                            meta.title = track.title.unwrap_or_default().to_string();
+                            meta.artist = track.artist.unwrap_or_default().to_string();
+                            meta.album = track.album.unwrap_or_default().to_string();
+                            meta.album_artist = track.album_artist.unwrap_or_default().to_string();
+                            meta.genre = track.genre.unwrap_or_default().to_string();
+                            meta.duration = track.duration.as_secs() as u32;
+                            meta.track_num = track.track_number.and_then(|t| u32::try_from(t).ok());
+                            meta.disc = track.disc_number.and_then(|d| u32::try_from(d).ok());
+                        }
+                        if tags::title_if_empty(&mut meta, &sname) {
+                            tag_error.push((sname.clone(), String::new()));
+                            summary.errors.push((cpath.clone(), String::from("Failed to read tags")));
+                        }
+                        db.add_track(&sname, &meta, &track.analysis);
+                        analysed += 1;
+                    }
+                }
+            }
+            Err(e) => {
+                failed.push((sname.clone(), format!("{}", e)));
+                // Failures of temporary (embedded, or rewritten) cue files cannot be recorded
+                if path.starts_with(mpath) {
+                    db.add_failure(&sname, &format!("{}", e));
+                }
+                summary.errors.push((String::from(path.to_string_lossy()), format!("{}", e)));
+            }
+        };
+
+        for (file, reason) in &failed[num_failed..] {
+            notifier.failure(file, reason, progress.position() * 100 / (total as u64));
+        }
+
+        if inc_progress {
+            progress.inc(1);
+            rate.inc();
+            let eta = rate.eta(total as u64 - progress.position());
+            if opts.progress_json {
+                let update = json!({
+                    "phase": "analyse",
+                    "done": progress.position(),
+                    "total": total,
+                    "current": sname,
+                    "failed": summary.failed + failed.len(),
+                    "eta": eta,
+                });
+                eprintln!("{}", update);
+            } else if last_progress_log.elapsed() >= PROGRESS_LOG_TIME {
+                let mut msg = format!("{}/{} analysed ({}%)", progress.position(), total, progress.position() * 100 / (total as u64));
+                if let (Some(per_minute), Some(eta)) = (rate.per_minute(), eta) {
+                    msg.push_str(&format!(", {:.0} files/min, ETA {}", per_minute, format_eta(eta)));
+                }
+                if opts.show_progress {
+                    // Progress bar is only drawn on screen, so log file gets percentage instead
+                    logging::file_only(log::Level::Info, &msg);
+                } else {
+                    log::info!("{}", msg);
+                }
+                last_progress_log = Instant::now();
+            }
+            notifier.progress(&sname, progress.position() * 100 / (total as u64), eta);
+            status.inc(&sname, summary.failed + failed.len());
+        }
+
+        if terminated() {
+            log::info!("Analysis interrupted");
+            break;
+        }
+
+        // Many failures in a row implies something is wrong with environment (e.g. disk
+        // unmounted) rather than with the files themselves.
+        if failed.len() > num_failed {
+            consecutive_failures += 1;
+            if opts.max_consecutive_failures > 0 && consecutive_failures >= opts.max_consecutive_failures {
+                log::error!("Aborting analysis, {} consecutive failures", consecutive_failures);
+                summary.aborted = true;
+                break;
+            }
+        } else {
+            consecutive_failures = 0;
+        }
+    }
+
+    // A cue file that contains no tracks produces no results, so report these
+    let mut empty_cues: Vec<String> = if terminated() || summary.aborted { Vec::new() } else { cue_files.into_iter().collect() };
+    empty_cues.sort();
+    for cue_file in empty_cues {
+        let pbuff = PathBuf::from(&cue_file);
+        let sname = match pbuff.strip_prefix(mpath) {
+            Ok(stripped) => String::from(stripped.to_string_lossy()),
+            Err(_) => cue_file.clone(),
+        };
+        log::warn!("No tracks found in '{}'", sname);
+        failed.push((sname.clone(), String::from("No tracks found in cue file")));
+        if pbuff.starts_with(mpath) {
+            db.add_failure(&sname, "No tracks found in cue file");
+        }
+        summary.errors.push((cue_file.clone(), String::from("No tracks found in cue file")));
+        progress.inc(1);
+        status.inc(&sname, summary.failed + failed.len());
+    }
+
+    progress.finish_with_message("Finished!");
+    summary.attempted += progress.position() as usize;
+    log::info!(target: logging::SUMMARY, "{} Analysed. {} Failure(s).", analysed, failed.len());
+    file_times.show_slowest();
+    show_errors(&failed, &tag_error, opts);
+    summary.analysed += analysed;
+    summary.failed += failed.len();
+    summary.tag_errors += tag_error.len();
+    Ok(())
+}
+
+// Finished notification is not sent if analysis was stopped by user
+fn send_finished(notifier: &mut notify::Notifier, message: &str, summary: &Summary) {
+    if terminated() {
+        log::info!("Analysis terminated by user");
+    } else {
+        notifier.finished(message, summary.analysed, summary.failed, &summary.errors);
+    }
+}
+
+fn finish_status(status: &mut status::StatusFile, summary: &Summary) {
+    let state = if terminated() {
+        status::STATE_TERMINATED
+    } else if summary.aborted {
+        status::STATE_ABORTED
+    } else {
+        status::STATE_FINISHED
+    };
+    status.finish(state, json!({
+        "analysed": summary.analysed,
+        "failed": summary.failed,
+        "tag_errors": summary.tag_errors,
+    }));
+}
+
+fn update_cue_sheets(db: &db::Db, cue_sheets: Vec<CueSheet>, dry_run: bool) {
+    for sheet in cue_sheets {
+        if sheet.changed {
+            log::info!("Cue file of '{}' has changed, its tracks will be re-analysed", sheet.file);
+            if dry_run {
+                report::removed(&sheet.file, "CUE file has changed");
+            } else {
+                db.remove_cue_tracks(&sheet.file);
+            }
+        } else if !dry_run {
+            db.set_cue_hash(&sheet.file, &sheet.hash);
+        }
+    }
+}
+
+pub fn analyse_files(db_path: &str, mpaths: &Vec<PathBuf>, opts: &Options, notifier: &mut notify::Notifier) -> Result<Summary, Error> {
+    let mut db = db::Db::new(&String::from(db_path))?;
+    let mut track_count_left = opts.max_num_files;
+    let mut num_files: usize = 0;
+    let mut num_cue_files: usize = 0;
+    let mut num_remaining: usize = 0;
+    let mut paths_not_scanned: usize = 0;
+    let mut summary = Summary::default();
+    let mut status = status::StatusFile::new(if opts.dry_run { "" } else { &opts.status_file });
+
+    if opts.dry_run {
+        // Collect everything that would be done into a single report, and ensure that
+        // nothing is written to DB
+        db.init_dry_run()?;
+        report::start();
+    } else {
+        db.init()?;
+    }
+    if opts.retry_failed && !opts.dry_run {
+        log::info!("Retrying {} previously failed file(s)", db.clear_failures());
+    }
+    if !opts.dry_run {
+        db.set_info(db::INFO_ANALYSER_VERSION, env!("CARGO_PKG_VERSION"));
+        db.set_info(db::INFO_FEATURES_VERSION, &bliss_audio::FEATURES_VERSION.to_string());
+        notifier.send(notify::EVENT_START, "Starting analysis", 0);
+    }
+
+    if !opts.keep_old {
+        db.remove_old(mpaths, opts.dry_run, opts.max_threads, &opts.moved_from, if opts.force_remove { 100 } else { opts.max_remove_percent });
+    }
+
+    for (index, path) in mpaths.iter().enumerate() {
+        let mpath = path.clone();
+        let cur = path.clone();
+        let mut track_paths: Vec<String> = Vec::new();
+        let mut cue_sheets: Vec<CueSheet> = Vec::new();
+        let mut skipped: usize = 0;
+        let mut durations = DurationSkips::default();
+
+        if mpaths.len() > 1 {
+            log::info!("Looking for new files in {}", mpath.to_string_lossy());
+        } else {
+            log::info!("Looking for new files");
+        }
+        get_file_list(&mut db, &mpath, &cur, &mut track_paths, &mut cue_sheets, &mpaths[..index], &mut skipped, &mut durations, opts);
+        if terminated() {
+            // Scan was interrupted, so file list is incomplete
+            break;
+        }
+        update_cue_sheets(&db, cue_sheets, opts.dry_run);
+        sort_track_paths(&mut track_paths, opts.order);
+        log::info!("Num new files: {}", track_paths.len());
+        if durations.too_short > 0 {
+            log::info!("Num files shorter than {} seconds: {}", opts.min_duration, durations.too_short);
+        }
+        if durations.too_long > 0 {
+            log::info!("Num files longer than {} seconds: {}", opts.max_duration, durations.too_long);
+        }
+        // Counts for this folder are the change in totals
+        let before = Summary {
+            analysed: summary.analysed,
+            failed: summary.failed,
+            discovered: summary.discovered,
+            attempted: summary.attempted,
+            skipped: summary.skipped,
+            ..Summary::default()
+        };
+        summary.discovered += track_paths.len();
+        summary.skipped += skipped;
+
+        if opts.dry_run {
+            for track in track_paths {
+                if track.ends_with(".cue") {
+                    report::cue(&track);
+                } else {
+                    report::analyse(&track);
+                }
+            }
+        } else {
+            if opts.max_num_files > 0 {
+                if track_paths.len() > track_count_left {
+                    log::info!("Only analysing {} files", track_count_left);
+                    num_remaining += track_paths.len() - track_count_left;
+                    track_paths.truncate(track_count_left);
+                }
+                track_count_left -= track_paths.len();
+            }
+            status.add_found(track_paths.len());
+            let cue_count = track_paths.iter().filter(|p| p.ends_with(".cue")).count();
+            num_cue_files += cue_count;
+            num_files += track_paths.len() - cue_count;
+
+            if !track_paths.is_empty() {
+                match analyse_new_files(&db, &mpath, track_paths, opts, notifier, &mut status, &mut summary) {
+                    Ok(_) => { }
+                    Err(e) => { log::error!("Analysis returned error: {}", e); }
+                }
+            } else {
+                log::info!("No new files to analyse");
+            }
+
+            let folder = Summary {
+                analysed: summary.analysed - before.analysed,
+                failed: summary.failed - before.failed,
+                discovered: summary.discovered - before.discovered,
+                attempted: summary.attempted - before.attempted,
+                skipped: summary.skipped - before.skipped,
+                ..Summary::default()
+            };
+            folder.log(&format!("Summary for {}:", mpath.to_string_lossy()));
+
+            if terminated() || summary.aborted {
+                break;
+            }
+
+            if opts.max_num_files > 0 && track_count_left <= 0 {
+                log::info!("File limit reached");
+                paths_not_scanned = mpaths.len() - (index + 1);
+                break;
+            }
+        }
+    }
+
+    if opts.max_num_files > 0 && !opts.dry_run {
+        log::info!(target: logging::SUMMARY, "Handled {} file(s) and {} CUE file(s). {} file(s) remaining.", num_files, num_cue_files, num_remaining);
+        if paths_not_scanned > 0 {
+            log::info!("{} music folder(s) not scanned, as file limit reached.", paths_not_scanned);
+        }
+    }
+
+    if !opts.errors_file.is_empty() && !opts.dry_run {
+        write_errors_file(&opts.errors_file, &summary.errors);
+    }
+    if let Some(report) = report::finish() {
+        report.log();
+        if !opts.report_file.is_empty() {
+            match report.write(&opts.report_file) {
+                Ok(_) => { log::info!("Report written to {}", opts.report_file); }
+                Err(e) => { log::error!("{}", e); }
+            }
+        }
+    }
+    if !opts.dry_run {
+        finish_status(&mut status, &summary);
+    }
+    if opts.progress_json && !opts.dry_run {
+        let update = json!({
+            "phase": "finished",
+            "analysed": summary.analysed,
+            "failed": summary.failed,
+            "tag_errors": summary.tag_errors,
+        });
+        eprintln!("{}", update);
+    }
+
+    cue::remove_temp();
+    db.close();
+    if !opts.dry_run {
+        if mpaths.len() > 1 {
+            summary.log("Summary:");
+        }
+        send_finished(notifier, &format!("Finished. Discovered: {}, analysed: {}, failed: {}, skipped: {}, not attempted: {}",
+                                         summary.discovered, summary.analysed, summary.failed, summary.skipped, summary.not_attempted()), &summary);
+    }
+    Ok(summary)
+}
+
+// Queue a file that has been created or modified. For CUE files, and audio files with
+// a CUE file, any existing tracks are removed so that these are re-analysed.
+fn queue_changed_file(db: &db::Db, mpath: &Path, pb: &Path, track_paths: &mut Vec<String>, earlier: &[PathBuf], opts: &Options) {
+    let ext = pb.extension().unwrap_or_default().to_string_lossy().to_string();
+    let audio = if ext.eq_ignore_ascii_case("cue") {
+        opts.extensions.iter().map(|e| pb.with_extension(e)).find(|p| p.is_file())
+    } else if valid_extension(opts, &ext) {
+        Some(pb.to_path_buf())
+    } else {
+        None
+    };
+    if_chain! {
+        if let Some(audio) = audio;
+        if let Ok(stripped) = audio.strip_prefix(mpath);
+        if !in_earlier_music_folder(&audio, stripped, earlier) && !too_small(&audio) && valid_utf8(&audio);
+        then {
+            let sname = String::from(stripped.to_string_lossy());
+            let cue_file = audio.with_extension("cue");
+            // File has changed, so retry even if it previously failed
+            db.remove_failure(&sname);
+            db.remove_failure(&stripped.with_extension("cue").to_string_lossy());
+            if cue_file.exists() {
+                db.remove_cue_tracks(&sname);
+                track_paths.push(cue_path_to_analyse(&cue_file, track_paths.len()));
+            } else {
+                match check_embedded_cue(&audio, track_paths.len()) {
+                    Some(cue_path) => {
+                        db.remove_cue_tracks(&sname);
+                        track_paths.push(String::from(cue_path.to_string_lossy()));
+                    }
+                    None => { track_paths.push(String::from(audio.to_string_lossy())); }
+                }
             }
         }
     }
 }
 
-fn check_dir_entry(db: &mut db::Db, mpath: &Path, entry: DirEntry, track_paths: &mut Vec<String>) {
-    let pb = entry.path();
-    if pb.is_dir() {
-        let check = pb.join(DONT_ANALYSE);
-        if check.exists() {
-            log::info!("Skipping '{}', found '{}'", pb.to_string_lossy(), DONT_ANALYSE);
-        } else {
-            get_file_list(db, mpath, &pb, track_paths);
+fn in_excluded_folder(mpath: &Path, path: &Path, opts: &Options) -> bool {
+    if excluded(opts, mpath, path) || in_hidden_or_junk(opts, mpath, path) {
+        return true;
+    }
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if skip_marker(opts, d).is_some() || excluded(opts, mpath, d) {
+            return true;
+        }
+        if d == mpath {
+            break;
         }
-    } else if pb.is_file() {
+        dir = d.parent();
+    }
+    false
+}
+
+/// Update DB for files (or folders) that have been created, modified, or removed.
+/// Returns true if DB was changed.
+pub fn analyse_changed(db_path: &str, mpaths: &Vec<PathBuf>, changed: &Vec<PathBuf>, removed: &Vec<PathBuf>, opts: &Options, notifier: &mut notify::Notifier) -> Result<bool, Error> {
+    let mut db = db::Db::new(&String::from(db_path))?;
+    let mut summary = Summary::default();
+    let mut status = status::StatusFile::new(&opts.status_file);
+    let mut modified = false;
+    db.init()?;
+
+    for path in removed {
         if_chain! {
-            if let Some(ext) = pb.extension();
-            let ext = ext.to_string_lossy();
-            if VALID_EXTENSIONS.contains(&&*ext);
-            if let Ok(stripped) = pb.strip_prefix(mpath);
+            if let Some(index) = mpaths.iter().position(|m| path.starts_with(m));
+            if let Ok(stripped) = path.strip_prefix(&mpaths[index]);
+            if !stripped.as_os_str().is_empty();
             then {
                 let sname = String::from(stripped.to_string_lossy());
-                let mut cue_file = pb.clone();
-                cue_file.set_extension("cue");
-                if cue_file.exists() {
-                    // For cue files, check if first track is in DB
-                    let mut cue_track_path = pb.clone();
-                    let ext = pb.extension().unwrap().to_string_lossy();
-                    cue_track_path.set_extension(format!("{}{}1", ext, db::CUE_MARKER));
-                    if let Ok(cue_track_stripped) = cue_track_path.strip_prefix(mpath) {
-                        let cue_track_sname = String::from(cue_track_stripped.to_string_lossy());
-                        if let Ok(id) = db.get_rowid(&cue_track_sname) {
-                            if id<=0 {
-                                track_paths.push(String::from(cue_file.to_string_lossy()));
-                            }
-                        }
-                    }
-                } else {
-                    if let Ok(id) = db.get_rowid(&sname) {
-                        if id<=0 {
-                            track_paths.push(String::from(pb.to_string_lossy()));
-                        }
-                    }
+                let count = db.remove_path(&sname);
+                if count > 0 {
+                    log::info!("Removed {} track(s) of '{}'", count, sname);
+                    modified = true;
                 }
             }
         }
     }
-}
 
-pub fn analyse_new_files(db: &db::Db, mpath: &PathBuf, track_paths: Vec<String>, max_threads: usize) -> Result<()> {
-    let total = track_paths.len();
-    let progress = ProgressBar::new(total.try_into().unwrap()).with_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] [{bar:25}] {percent:>3}% {pos:>6}/{len:6} {wide_msg}")
-            .progress_chars("=> "),
-    );
-    let cpu_threads: NonZeroUsize = match max_threads {
-        0 => NonZeroUsize::new(num_cpus::get()).unwrap(),
-        _ => NonZeroUsize::new(max_threads).unwrap(),
-    };
+    for (index, mpath) in mpaths.iter().enumerate() {
+        let mut track_paths: Vec<String> = Vec::new();
+        let mut cue_sheets: Vec<CueSheet> = Vec::new();
+        let mut skipped: usize = 0;
+        let mut durations = DurationSkips::default();
+        for path in changed {
+            if mpaths.iter().position(|m| path.starts_with(m)) != Some(index) || in_excluded_folder(mpath, path, opts) {
+                continue;
+            }
+            if path.is_dir() {
+                if skip_marker(opts, path).is_none() {
+                    get_file_list(&mut db, mpath, path, &mut track_paths, &mut cue_sheets, &mpaths[..index], &mut skipped, &mut durations, opts);
+                }
+            } else if path.is_file() {
+                queue_changed_file(&db, mpath, path, &mut track_paths, &mpaths[..index], opts);
+            }
+        }
+        update_cue_sheets(&db, cue_sheets, false);
+        track_paths.sort();
+        track_paths.dedup();
+        if !track_paths.is_empty() {
+            log::info!("Num new or changed files: {}", track_paths.len());
+            status.add_found(track_paths.len());
+            summary.discovered += track_paths.len();
+            match analyse_new_files(&db, mpath, track_paths, opts, notifier, &mut status, &mut summary) {
+                Ok(_) => { }
+                Err(e) => { log::error!("Analysis returned error: {}", e); }
+            }
+            modified = true;
+        }
+        if terminated() || summary.aborted {
+            break;
+        }
+    }
 
-    let mut analysed = 0;
-    let mut failed: Vec<String> = Vec::new();
-    let mut tag_error: Vec<String> = Vec::new();
-    let mut reported_cue:HashSet<String> = HashSet::new();
+    if !opts.errors_file.is_empty() && !summary.errors.is_empty() {
+        write_errors_file(&opts.errors_file, &summary.errors);
+    }
+    finish_status(&mut status, &summary);
 
-    log::info!("Analysing new files");
-    for (path, result) in <FFmpeg as Decoder>::analyze_paths_with_cores(track_paths, cpu_threads) {
-        let stripped = path.strip_prefix(mpath).unwrap();
-        let spbuff = stripped.to_path_buf();
-        let sname = String::from(spbuff.to_string_lossy());
-        progress.set_message(format!("{}", sname));
-        let mut inc_progress = true; // Only want to increment progress once for cue tracks
-        match result {
-            Ok(track) => {
-                let cpath = String::from(path.to_string_lossy());
-                match track.cue_info {
-                    Some(cue) => {
-                        match track.track_number {
-                            Some(track_num) => {
-                                if reported_cue.contains(&cpath) {
-                                    inc_progress = false;
-                                } else {
-                                    analysed += 1;
-                                    reported_cue.insert(cpath);
-                                }
-                                let meta = db::Metadata {
-                                    title: track.title.unwrap_or_default().to_string(),
-                                    artist: track.artist.unwrap_or_default().to_string(),
-                                    album: track.album.unwrap_or_default().to_string(),
-                                    album_artist: track.album_artist.unwrap_or_default().to_string(),
-                                    genre: track.genre.unwrap_or_default().to_string(),
-                                    duration: track.duration.as_secs() as u32
-                                };
-
-                                // Remove prefix from audio_file_path
-                                let pbuff = PathBuf::from(&cue.audio_file_path);
-                                let stripped = pbuff.strip_prefix(mpath).unwrap();
-                                let spbuff = stripped.to_path_buf();
-                                let sname = String::from(spbuff.to_string_lossy());
+    cue::remove_temp();
+    db.close();
+    Ok(modified)
+}
 
-                                let db_path = format!("{}{}{}", sname, db::CUE_MARKER, track_num);
-                                db.add_track(&db_path, &meta, &track.analysis);
-                            }
-                            None => { failed.push(format!("{} - No track number?", sname)); }
-                        }
-                    }
-                    None => {
-                        // Use lofty to read tags here, and not bliss's, so that if update
-                        // tags is ever used they are from the same source.
-                        let mut meta = tags::read(&cpath);
-                        if meta.is_empty() {
-                            // Lofty failed? Try from bliss...
-                            meta.title = track.title.unwrap_or_default().to_string();
-                            meta.artist = track.artist.unwrap_or_default().to_string();
-                            meta.album = track.album.unwrap_or_default().to_string();
-                            meta.album_artist = track.album_artist.unwrap_or_default().to_string();
-                            meta.genre = track.genre.unwrap_or_default().to_string();
-                            meta.duration = track.duration.as_secs() as u32;
-                        }
-                        if meta.is_empty() {
-                            tag_error.push(sname.clone());
-                        }
-                        db.add_track(&sname, &meta, &track.analysis);
-                        analysed += 1;
-                    }
+// Check if file, or tracks of its CUE file, are now in DB
+fn analysed_ok(db: &db::Db, mpath: &Path, path: &PathBuf, sname: &str, opts: &Options) -> bool {
+    let ext = path.extension().unwrap_or_default().to_string_lossy().to_string();
+    if ext.eq_ignore_ascii_case("cue") {
+        return opts.extensions.iter().map(|e| path.with_extension(e)).any(|p| p.is_file() && cue_track_in_db(db, mpath, &p));
+    }
+    matches!(db.get_rowid(sname), Ok(id) if id > 0) || cue_track_in_db(db, mpath, path)
+}
+
+/// Re-analyse only those files that previously failed, without scanning music folders.
+/// Files that are now analysed are removed from the list of failures.
+pub fn retry_failures(db_path: &str, mpaths: &Vec<PathBuf>, opts: &Options, notifier: &mut notify::Notifier) -> Result<Summary, Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    let mut summary = Summary::default();
+    let mut status = status::StatusFile::new(&opts.status_file);
+    db.init()?;
+
+    let failures = db.get_failures();
+    log::info!("Num previously failed files: {}", failures.len());
+    notifier.send(notify::EVENT_START, "Retrying failed files", 0);
+    let mut remaining: Vec<&(String, String)> = failures.iter().collect();
+    for mpath in mpaths {
+        let mut track_paths: Vec<String> = Vec::new();
+        let mut retried: Vec<(PathBuf, String)> = Vec::new();
+        remaining.retain(|(file, error)| {
+            let path = db::fs_path(mpath, file);
+            if !path.is_file() {
+                return true;
+            }
+            log::debug!("Retrying '{}', previously failed with: {}", file, error);
+            if path.extension().unwrap_or_default().to_string_lossy().eq_ignore_ascii_case("cue") {
+                track_paths.push(cue_path_to_analyse(&path, track_paths.len()));
+            } else {
+                match check_embedded_cue(&path, track_paths.len()) {
+                    Some(cue_path) => { track_paths.push(String::from(cue_path.to_string_lossy())); }
+                    None => { track_paths.push(String::from(path.to_string_lossy())); }
                 }
             }
-            Err(e) => { failed.push(format!("{} - {}", sname, e)); }
-        };
+            retried.push((path, file.clone()));
+            false
+        });
+        if track_paths.is_empty() {
+            continue;
+        }
+        status.add_found(track_paths.len());
+        summary.discovered += track_paths.len();
+        match analyse_new_files(&db, mpath, track_paths, opts, notifier, &mut status, &mut summary) {
+            Ok(_) => { }
+            Err(e) => { log::error!("Analysis returned error: {}", e); }
+        }
+        for (path, file) in &retried {
+            if analysed_ok(&db, mpath, path, file, opts) {
+                db.remove_failure(file);
+            }
+        }
+        if terminated() || summary.aborted {
+            break;
+        }
+    }
 
-        if inc_progress {
-            progress.inc(1);
+    // Files that no longer exist cannot be retried
+    if !terminated() && !summary.aborted {
+        for (file, _) in remaining {
+            log::info!("Removing failure of '{}', file no longer exists", file);
+            db.remove_failure(file);
         }
     }
 
-    progress.finish_with_message("Finished!");
-    log::info!("{} Analysed. {} Failure(s).", analysed, failed.len());
-    if !failed.is_empty() {
-        let total = failed.len();
-        failed.truncate(MAX_ERRORS_TO_SHOW);
+    if !opts.errors_file.is_empty() {
+        write_errors_file(&opts.errors_file, &summary.errors);
+    }
+    finish_status(&mut status, &summary);
+    cue::remove_temp();
+    db.close();
+    send_finished(notifier, &format!("Finished. Retried: {}, analysed: {}, failed: {}", summary.attempted, summary.analysed, summary.failed), &summary);
+    Ok(summary)
+}
 
-        log::error!("Failed to analyse the following file(s):");
-        for err in failed {
-            log::error!("  {}", err);
+/// Read list of files to analyse, one per line, from file or stdin ('-').
+pub fn read_file_list(path: &str) -> Result<Vec<PathBuf>, Error> {
+    let reader: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => { return Err(Error::Config(format!("Failed to open file list ({}). {}", path, e))); }
         }
-        if total > MAX_ERRORS_TO_SHOW {
-            log::error!("  + {} other(s)", total - MAX_ERRORS_TO_SHOW);
+    };
+    let mut files: Vec<PathBuf> = Vec::new();
+    for line in reader.lines() {
+        match line {
+            Ok(line) => {
+                let line = line.trim();
+                if !line.is_empty() && !line.starts_with("#") {
+                    files.push(PathBuf::from(line));
+                }
+            }
+            Err(e) => { return Err(Error::Config(format!("Failed to read file list ({}). {}", path, e))); }
         }
     }
-    if !tag_error.is_empty() {
-        let total = tag_error.len();
-        tag_error.truncate(MAX_TAG_ERRORS_TO_SHOW);
+    Ok(files)
+}
 
-        log::error!("Failed to read tags of the following file(s):");
-        for err in tag_error {
-            log::error!("  {}", err);
-        }
-        if total > MAX_TAG_ERRORS_TO_SHOW {
-            log::error!("  + {} other(s)", total - MAX_TAG_ERRORS_TO_SHOW);
+// Check if listed file was analysed, and has not (now) failed
+fn listed_file_ok(db: &db::Db, mpath: &Path, path: &PathBuf, opts: &Options) -> bool {
+    match path.strip_prefix(mpath) {
+        Ok(stripped) => {
+            let sname = String::from(stripped.to_string_lossy());
+            !db.has_failure(&sname) && !db.has_failure(&stripped.with_extension("cue").to_string_lossy()) && analysed_ok(db, mpath, path, &sname, opts)
         }
+        Err(_) => false,
     }
-    Ok(())
 }
 
-pub fn analyse_files(db_path: &str, mpaths: &Vec<PathBuf>, dry_run: bool, keep_old: bool, max_num_tracks: usize, max_threads: usize) {
-    let mut db = db::Db::new(&String::from(db_path));
-    let mut track_count_left = max_num_tracks;
-
-    db.init();
+/// Analyse (or re-analyse) only the listed files. Files must be within a music folder,
+/// so that the path stored in DB is relative to this.
+pub fn analyse_listed(db_path: &str, mpaths: &Vec<PathBuf>, files: &Vec<PathBuf>, opts: &Options, notifier: &mut notify::Notifier) -> Result<Summary, Error> {
+    // Listed paths may be relative to current folder, so compare absolute paths
+    let mpaths: Vec<PathBuf> = mpaths.iter().map(|p| p.canonicalize().unwrap_or(p.clone())).collect();
+    let db = db::Db::new(&String::from(db_path))?;
+    let mut summary = Summary::default();
+    let mut status = status::StatusFile::new(&opts.status_file);
+    let mut queued: Vec<Vec<PathBuf>> = vec![Vec::new(); mpaths.len()];
+    db.init()?;
 
-    if !keep_old {
-        db.remove_old(mpaths, dry_run);
+    for file in files {
+        let path = match file.canonicalize() {
+            Ok(path) => path,
+            Err(_) => {
+                log::error!("Rejecting '{}', file does not exist", file.to_string_lossy());
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        let ext = path.extension().unwrap_or_default().to_string_lossy().to_string();
+        if !path.is_file() || !(ext.eq_ignore_ascii_case("cue") || valid_extension(opts, &ext)) {
+            log::error!("Rejecting '{}', not a supported audio or CUE file", file.to_string_lossy());
+            summary.skipped += 1;
+            continue;
+        }
+        if is_apple_double(&path.file_name().unwrap_or_default().to_string_lossy()) {
+            log::error!("Rejecting '{}', is a macOS resource fork", file.to_string_lossy());
+            summary.skipped += 1;
+            continue;
+        }
+        if path.to_str().is_none() {
+            log::error!("Rejecting '{}', path is not valid UTF-8", file.to_string_lossy());
+            summary.skipped += 1;
+            continue;
+        }
+        match mpaths.iter().position(|m| path.starts_with(m)) {
+            Some(index) => { queued[index].push(path); }
+            None => {
+                log::error!("Rejecting '{}', not within a music folder", file.to_string_lossy());
+                summary.skipped += 1;
+            }
+        }
     }
 
-    for path in mpaths {
-        let mpath = path.clone();
-        let cur = path.clone();
+    notifier.send(notify::EVENT_START, "Analysing listed files", 0);
+    for (index, mpath) in mpaths.iter().enumerate() {
         let mut track_paths: Vec<String> = Vec::new();
-
-        if mpaths.len() > 1 {
-            log::info!("Looking for new files in {}", mpath.to_string_lossy());
-        } else {
-            log::info!("Looking for new files");
+        for path in &queued[index] {
+            queue_changed_file(&db, mpath, path, &mut track_paths, &mpaths[..index], opts);
         }
-        get_file_list(&mut db, &mpath, &cur, &mut track_paths);
         track_paths.sort();
-        log::info!("Num new files: {}", track_paths.len());
+        track_paths.dedup();
+        if track_paths.is_empty() {
+            continue;
+        }
+        status.add_found(track_paths.len());
+        summary.discovered += track_paths.len();
+        match analyse_new_files(&db, mpath, track_paths, opts, notifier, &mut status, &mut summary) {
+            Ok(_) => { }
+            Err(e) => { log::error!("Analysis returned error: {}", e); }
+        }
+        if terminated() || summary.aborted {
+            break;
+        }
+    }
 
-        if dry_run {
-            if !track_paths.is_empty() {
-                log::info!("The following need to be analysed:");
-                for track in track_paths {
-                    log::info!("  {}", track);
-                }
-            }
-        } else {
-            if max_num_tracks > 0 {
-                if track_paths.len() > track_count_left {
-                    log::info!("Only analysing {} files", track_count_left);
-                    track_paths.truncate(track_count_left);
-                }
-                track_count_left -= track_paths.len();
+    log::info!("Results:");
+    for (index, mpath) in mpaths.iter().enumerate() {
+        for path in &queued[index] {
+            if listed_file_ok(&db, mpath, path, opts) {
+                log::info!("  OK: {}", path.to_string_lossy());
+            } else {
+                log::error!("  FAILED: {}", path.to_string_lossy());
             }
+        }
+    }
 
-            if !track_paths.is_empty() {
-                match analyse_new_files(&db, &mpath, track_paths, max_threads) {
-                    Ok(_) => { }
-                    Err(e) => { log::error!("Analysis returned error: {}", e); }
-                }
+    if !opts.errors_file.is_empty() {
+        write_errors_file(&opts.errors_file, &summary.errors);
+    }
+    finish_status(&mut status, &summary);
+    cue::remove_temp();
+    db.close();
+    summary.log("Summary:");
+    send_finished(notifier, &format!("Finished. Analysed: {}, failed: {}, rejected: {}", summary.analysed, summary.failed, summary.skipped), &summary);
+    Ok(summary)
+}
+
+pub fn show_info(db_path: &str) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    log::info!("Analyser version: {}", env!("CARGO_PKG_VERSION"));
+    log::info!("Decoder: ffmpeg");
+    log::info!("Features version: {}", bliss_audio::FEATURES_VERSION);
+    log::info!("DB schema version: {}", db.get_version());
+    // Info table, and track count, will not exist if DB was never analysed by this version
+    log::info!("DB analyser version: {}", db.get_info(db::INFO_ANALYSER_VERSION).unwrap_or("Unknown".to_string()));
+    match db.get_info(db::INFO_FEATURES_VERSION) {
+        Some(version) => {
+            if version != bliss_audio::FEATURES_VERSION.to_string() {
+                log::warn!("DB features version: {} (does not match, tracks should be re-analysed)", version);
             } else {
-                log::info!("No new files to analyse");
+                log::info!("DB features version: {}", version);
+            }
+        }
+        None => { log::info!("DB features version: Unknown"); }
+    }
+    log::info!("DB tracks: {}", db.get_track_count());
+    let versions = db.get_analysis_versions();
+    if !versions.is_empty() {
+        log::info!("DB tracks per features version:");
+        let mut stale: usize = 0;
+        for (version, count) in &versions {
+            match version {
+                Some(version) => { log::info!("  {}: {}", version, count); }
+                None => { log::info!("  Unknown: {}", count); }
             }
+            if version.map_or(true, |v| v < bliss_audio::FEATURES_VERSION) {
+                stale += count;
+            }
+        }
+        if stale > 0 {
+            log::warn!("{} track(s) were analysed by an older version, use --refresh-stale to re-analyse these", stale);
+        }
+    }
+    if let Some((oldest, newest)) = db.get_analysed_range() {
+        log::info!("DB oldest analysis: {}", format_timestamp(oldest));
+        log::info!("DB newest analysis: {}", format_timestamp(newest));
+    }
+    db.close();
+    Ok(())
+}
 
-            if max_num_tracks > 0 && track_count_left <= 0 {
-                log::info!("File limit reached");
-                break;
+fn format_timestamp(secs: i64) -> String {
+    match Local.timestamp_opt(secs, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => secs.to_string(),
+    }
+}
+
+/// Report tracks with invalid analysis. If 'fix' is set these are removed from the
+/// DB, so that they are re-analysed the next time files are analysed.
+pub fn verify(db_path: &str, fix: bool) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    log::info!("Checking analysis of tracks");
+    let invalid = db.get_invalid_tracks();
+    log::info!("Num tracks with invalid analysis: {}", invalid.len());
+    let mut removed: HashSet<String> = HashSet::new();
+    for (file, reason) in &invalid {
+        log::warn!("  {} - {}", file, reason);
+        if fix {
+            // Tracks of a CUE file are only re-analysed if all are removed
+            let path = match file.find(db::CUE_MARKER) {
+                Some(pos) => file[..pos].to_string(),
+                None => file.clone(),
+            };
+            if removed.insert(path.clone()) {
+                db.remove_path(&path);
             }
         }
     }
+    if fix && !invalid.is_empty() {
+        log::info!("Removed {} file(s) from database, these will be re-analysed by the analyse task", removed.len());
+    }
+    db.close();
+    Ok(())
+}
 
+/// List tracks in DB whose files no longer exist. DB is not modified.
+pub fn find_orphans(db_path: &str, mpaths: &Vec<PathBuf>, absolute: bool) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    log::info!("Looking for non-existent tracks");
+    let orphans = db.get_orphans(mpaths, 0);
+    log::info!("Num non-existent tracks: {}", orphans.len());
+    for orphan in &orphans {
+        if absolute {
+            // File does not exist in any music folder, so just use the first
+            let path = mpaths.first().map(|m| db::fs_path(m, orphan)).unwrap_or(PathBuf::from(orphan));
+            log::info!("  {}", path.to_string_lossy());
+        } else {
+            log::info!("  {}", orphan);
+        }
+    }
     db.close();
+    Ok(())
 }
 
-pub fn read_tags(db_path: &str, mpaths: &Vec<PathBuf>) {
-    let db = db::Db::new(&String::from(db_path));
-    db.init();
+pub fn read_tags(db_path: &str, mpaths: &Vec<PathBuf>) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    db.init()?;
     db.update_tags(&mpaths);
     db.close();
+    Ok(())
+}
+
+// Check whether non-empty DB values differ from those in file
+fn tags_differ(dtags: &db::Metadata, ftags: &db::Metadata) -> bool {
+    let differ = |d: &String, f: &String| !d.is_empty() && d != f;
+    differ(&dtags.title, &ftags.title) || differ(&dtags.artist, &ftags.artist) || differ(&dtags.album_artist, &ftags.album_artist)
+        || differ(&dtags.album, &ftags.album) || differ(&dtags.genre, &ftags.genre)
+}
+
+/// Write metadata stored in DB to files, for files whose tags differ. Tracks of CUE
+/// files are not updated.
+pub fn sync_tags(db_path: &str, mpaths: &Vec<PathBuf>, dry_run: bool, preserve_mod_times: bool) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    if dry_run {
+        db.init_dry_run()?;
+    } else {
+        db.init()?;
+    }
+    let tracks = db.get_metadata();
+    db.close();
+
+    let mut updated: usize = 0;
+    let mut failed: usize = 0;
+    for track in tracks {
+        if terminated() {
+            break;
+        }
+        let dtags = db::Metadata {
+            title: track.title.unwrap_or_default(),
+            artist: track.artist.unwrap_or_default(),
+            album_artist: track.album_artist.unwrap_or_default(),
+            album: track.album.unwrap_or_default(),
+            genre: track.genre.unwrap_or_default(),
+            duration: track.duration,
+            track_num: track.track_num,
+            disc: track.disc,
+            year: track.year,
+            size: track.size,
+        };
+        let path = match mpaths.iter().map(|m| db::fs_path(m, &track.file)).find(|p| p.exists()) {
+            Some(path) => path,
+            None => {
+                log::debug!("Skipping '{}', file does not exist", track.file);
+                continue;
+            }
+        };
+        if !tags_differ(&dtags, &tags::read(&String::from(path.to_string_lossy()))) {
+            continue;
+        }
+        if dry_run {
+            log::info!("Would update '{}'", track.file);
+            updated += 1;
+        } else {
+            match tags::write(&path, &dtags, preserve_mod_times) {
+                Ok(_) => {
+                    log::debug!("Updated '{}'", track.file);
+                    updated += 1;
+                }
+                Err(e) => {
+                    log::error!("Failed to update '{}'. {}", track.file, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    log::info!("{} {}. {} Failure(s).", updated, if dry_run { "to update" } else { "Updated" }, failed);
+    Ok(())
 }
 
-pub fn update_ignore(db_path: &str, ignore_path: &PathBuf) {
-    let file = File::open(ignore_path).unwrap();
+pub fn update_ignore(db_path: &str, ignore_path: &PathBuf) -> Result<(), Error> {
+    let file = match File::open(ignore_path) {
+        Ok(file) => file,
+        Err(e) => { return Err(Error::Config(format!("Failed to open ignore file ({}). {}", ignore_path.to_string_lossy(), e))); }
+    };
     let reader = BufReader::new(file);
-    let db = db::Db::new(&String::from(db_path));
-    db.init();
+    let db = db::Db::new(&String::from(db_path))?;
+    db.init()?;
 
     db.clear_ignore();
     let mut lines = reader.lines();
@@ -280,4 +1977,240 @@ pub fn update_ignore(db_path: &str, ignore_path: &PathBuf) {
     }
 
     db.close();
+    Ok(())
+}
+
+/// Clear Ignore flag of items listed in ignore file, or of all tracks if 'all' is set.
+pub fn un_ignore(db_path: &str, ignore_path: &PathBuf, all: bool) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    db.init()?;
+
+    if all {
+        log::info!("Un-ignore all tracks");
+        db.clear_ignore();
+    } else {
+        let file = match File::open(ignore_path) {
+            Ok(file) => file,
+            Err(e) => { return Err(Error::Config(format!("Failed to open ignore file ({}). {}", ignore_path.to_string_lossy(), e))); }
+        };
+        let reader = BufReader::new(file);
+        let mut lines = reader.lines();
+        while let Some(Ok(line)) = lines.next() {
+            if !line.is_empty() && !line.starts_with("#") {
+                db.unset_ignore(&line);
+            }
+        }
+    }
+
+    db.close();
+    Ok(())
+}
+
+/// Convert paths in DB to configured Unicode form, merging any duplicates.
+pub fn normalise_paths(db_path: &str) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    db.init()?;
+    if !db.backup("converting paths") {
+        return Err(Error::Db(String::from("Failed to backup database, use --no-backup to convert paths without a backup")));
+    }
+    let count = db.normalise_paths()?;
+    db.close();
+    log::info!("Num paths converted: {}", count);
+    Ok(())
+}
+
+/// Remove tracks whose path starts with one of 'prefixes' from DB.
+pub fn remove_paths(db_path: &str, prefixes: &Vec<String>, dry_run: bool) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    if dry_run {
+        db.init_dry_run()?;
+    } else {
+        db.init()?;
+    }
+    let mut total: usize = 0;
+    for prefix in prefixes {
+        let count = if dry_run {
+            let paths = db.get_paths_with_prefix(prefix);
+            for path in &paths {
+                log::info!("  {}", path);
+            }
+            paths.len()
+        } else {
+            if !db.get_paths_with_prefix(prefix).is_empty() && !db.backup("removing tracks") {
+                return Err(Error::Db(String::from("Failed to backup database, use --no-backup to remove tracks without a backup")));
+            }
+            db.remove_prefix(prefix)?
+        };
+        log::info!("{}: {} track(s)", prefix, count);
+        total += count;
+    }
+    db.close();
+    log::info!("{} {} track(s)", if dry_run { "Would remove" } else { "Removed" }, total);
+    Ok(())
+}
+
+/// List ignored tracks, only those starting with one of 'prefixes' if any are given. If
+/// 'group' is set then the number of tracks per top-level folder is listed instead. If
+/// 'clear' is set (and this is not a dry run) then listed tracks are un-ignored.
+pub fn list_ignored(db_path: &str, prefixes: &Vec<String>, group: bool, output_json: bool, clear: bool, dry_run: bool) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    if dry_run {
+        db.init_dry_run()?;
+    } else {
+        db.init()?;
+    }
+    let mut ignored = db.get_ignored();
+    if !prefixes.is_empty() {
+        ignored.retain(|path| prefixes.iter().any(|p| db::path_matches(path, p)));
+    }
+    let total = db.get_track_count();
+
+    log::info!("Num ignored tracks: {} of {}", ignored.len(), total);
+    if group {
+        // Files directly within music folder are grouped under '.'
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for path in &ignored {
+            let folder = match path.split_once('/') {
+                Some((folder, _)) => folder,
+                None => ".",
+            };
+            *counts.entry(folder.to_string()).or_insert(0) += 1;
+        }
+        let mut folders: Vec<(String, usize)> = counts.into_iter().collect();
+        folders.sort();
+        if output_json {
+            let counts: serde_json::Map<String, serde_json::Value> = folders.into_iter().map(|(folder, count)| (folder, json!(count))).collect();
+            println!("{}", json!({"ignored": ignored.len(), "total": total, "folders": counts}));
+        } else {
+            for (folder, count) in folders {
+                println!("{}: {}", folder, count);
+            }
+        }
+    } else if output_json {
+        println!("{}", json!({"ignored": ignored.len(), "total": total, "tracks": ignored}));
+    } else {
+        for path in &ignored {
+            println!("{}", path);
+        }
+    }
+
+    if clear && !ignored.is_empty() {
+        if dry_run {
+            log::info!("Would un-ignore {} track(s)", ignored.len());
+        } else {
+            // Only the listed tracks are updated, so that nothing the user has not seen changes
+            let count = db.unset_ignore_files(&ignored);
+            log::info!("Un-ignored {} track(s)", count);
+            if count < ignored.len() {
+                log::info!("{} track(s) have not been analysed, so remain ignored", ignored.len() - count);
+            }
+        }
+    }
+    db.close();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Collects warnings, so that tests can check what was logged
+    struct WarningLogger;
+
+    static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl log::Log for WarningLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                WARNINGS.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: WarningLogger = WarningLogger;
+
+    fn options() -> Options {
+        Options {
+            dry_run: false,
+            report_file: String::new(),
+            keep_old: false,
+            max_remove_percent: 0,
+            force_remove: false,
+            moved_from: Vec::new(),
+            max_num_files: 0,
+            max_threads: 1,
+            max_errors: 0,
+            max_tag_errors: 0,
+            errors_file: String::new(),
+            show_progress: false,
+            progress_json: false,
+            max_consecutive_failures: 0,
+            status_file: String::new(),
+            retry_failed: false,
+            exclude: GlobSet::empty(),
+            exclude_patterns: Vec::new(),
+            force: false,
+            skip_markers: Vec::new(),
+            refresh_stale: false,
+            extensions: vec![String::from("mp3")],
+            order: Order::Path,
+            min_duration: 0,
+            max_duration: 0,
+            follow_symlinks: false,
+            include_hidden: false,
+            scan_cache: String::new(),
+            since: 0,
+        }
+    }
+
+    #[test]
+    fn decoder_chunks() {
+        assert_eq!(decoder_chunk_len(3, 8), 3);
+        assert_eq!(decoder_chunk_len(8, 8), 1);
+        assert_eq!(decoder_chunk_len(10, 4), 2);
+        assert_eq!(decoder_chunk_len(0, 4), 1);
+    }
+
+    #[test]
+    fn file_times_single_chunk() {
+        let paths: Vec<String> = (0..3).map(|i| format!("{}.mp3", i)).collect();
+        // Fewer files than threads, so all are analysed in order by one thread
+        let times = FileTimes::new(&paths, paths.len() + 1);
+        assert_eq!(times.chunks.values().copied().collect::<HashSet<usize>>(), HashSet::from([0]));
+        assert_eq!(times.last.len(), 1);
+    }
+
+    #[test]
+    fn cue_track_durations() {
+        assert_eq!(cue_track_duration(Duration::ZERO), None);
+        assert_eq!(cue_track_duration(Duration::from_millis(900)), Some(1));
+        assert_eq!(cue_track_duration(Duration::from_millis(200_400)), Some(200));
+        assert_eq!(cue_track_duration(Duration::from_millis(200_600)), Some(201));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scan_skips_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let _ = log::set_logger(&LOGGER).map(|()| log::set_max_level(log::LevelFilter::Warn));
+        let dir = std::env::temp_dir().join(format!("bliss-analyser-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Large enough to not be skipped as too small
+        fs::write(dir.join(OsStr::from_bytes(b"\xff.mp3")), vec![0u8; MIN_FILE_SIZE as usize]).unwrap();
+
+        let mut skipped: usize = 0;
+        let files = scan_folder(&dir, &dir, &[], &mut skipped, &options());
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(files.is_empty());
+        assert_eq!(skipped, 1);
+        assert!(WARNINGS.lock().unwrap().iter().any(|w| w.contains("path is not valid UTF-8")));
+    }
 }
@@ -0,0 +1,181 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::db;
+use crate::distance;
+use crate::error::Error;
+use crate::playlist;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+const MAX_ITERATIONS: usize = 100;
+const RANDOM_SEED: u64 = 0x2545f4914f6cdd1d;
+const CSV_FILE: &str = "clusters.csv";
+
+// Xorshift generator, with a fixed seed so that clusters are the same each run
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn new() -> Self {
+        Self {
+            state: RANDOM_SEED,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // Value in range 0..1
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn nearest(centres: &Vec<Vec<f64>>, vals: &[f64]) -> (usize, f64) {
+    let mut best = (0, f64::MAX);
+    for (index, centre) in centres.iter().enumerate() {
+        let dist = distance::euclidean(centre, vals);
+        if dist < best.1 {
+            best = (index, dist);
+        }
+    }
+    best
+}
+
+// Choose initial centres using k-means++, i.e. each new centre is picked with a
+// probability proportional to its squared distance from the existing centres.
+fn initial_centres(tracks: &Vec<&db::TrackAnalysis>, k: usize, random: &mut Random) -> Vec<Vec<f64>> {
+    let mut centres: Vec<Vec<f64>> = Vec::new();
+    centres.push(tracks[(random.next() % tracks.len() as u64) as usize].analysis.clone());
+    while centres.len() < k {
+        let weights: Vec<f64> = tracks.iter().map(|t| nearest(&centres, &t.analysis).1.powi(2)).collect();
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            // All remaining tracks are identical to a centre
+            centres.push(tracks[(random.next() % tracks.len() as u64) as usize].analysis.clone());
+            continue;
+        }
+        let mut target = random.next_f64() * total;
+        let mut chosen = tracks.len() - 1;
+        for (index, weight) in weights.iter().enumerate() {
+            if target < *weight {
+                chosen = index;
+                break;
+            }
+            target -= weight;
+        }
+        centres.push(tracks[chosen].analysis.clone());
+    }
+    centres
+}
+
+/// Group tracks into 'k' clusters, returns cluster index of each track.
+fn kmeans(tracks: &Vec<&db::TrackAnalysis>, k: usize) -> Vec<usize> {
+    let mut random = Random::new();
+    let mut centres = initial_centres(tracks, k, &mut random);
+    let mut assignments: Vec<usize> = vec![usize::MAX; tracks.len()];
+
+    for iteration in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (index, track) in tracks.iter().enumerate() {
+            let cluster = nearest(&centres, &track.analysis).0;
+            if assignments[index] != cluster {
+                assignments[index] = cluster;
+                changed = true;
+            }
+        }
+        if !changed {
+            log::debug!("Clusters converged after {} iteration(s)", iteration);
+            break;
+        }
+
+        // Move each centre to the mean of its tracks. Empty clusters keep their centre.
+        let dims = centres[0].len();
+        let mut sums: Vec<Vec<f64>> = vec![vec![0.0; dims]; k];
+        let mut counts: Vec<usize> = vec![0; k];
+        for (index, track) in tracks.iter().enumerate() {
+            counts[assignments[index]] += 1;
+            for (sum, val) in sums[assignments[index]].iter_mut().zip(track.analysis.iter()) {
+                *sum += val;
+            }
+        }
+        for ((centre, sum), count) in centres.iter_mut().zip(sums.iter()).zip(counts.iter()) {
+            if *count > 0 {
+                *centre = sum.iter().map(|s| s / *count as f64).collect();
+            }
+        }
+    }
+    assignments
+}
+
+/// Quote CSV field if required.
+pub fn csv_field(val: &str) -> String {
+    if val.contains(',') || val.contains('"') || val.contains('\n') {
+        format!("\"{}\"", val.replace("\"", "\"\""))
+    } else {
+        val.to_string()
+    }
+}
+
+fn write_csv(path: &PathBuf, tracks: &Vec<&db::TrackAnalysis>, assignments: &Vec<usize>) -> Result<(), Error> {
+    let res = File::create(path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "File,Cluster")?;
+        for (track, cluster) in tracks.iter().zip(assignments.iter()) {
+            writeln!(writer, "{},{}", csv_field(&track.file), cluster + 1)?;
+        }
+        writer.flush()
+    });
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::Config(format!("Failed to write CSV file ({}). {}", path.to_string_lossy(), e))),
+    }
+}
+
+/// Group tracks into 'k' clusters of similar tracks, and write an M3U playlist for
+/// each to 'out' folder. If 'csv' is set, a single CSV file mapping each track to its
+/// cluster is written instead.
+pub fn create(db_path: &str, mpaths: &Vec<PathBuf>, k: usize, out: &str, csv: bool) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    let all_tracks = db.get_analysis();
+    db.close();
+
+    // Ignored tracks should not be in mixes. CUE tracks cannot be in an M3U, but are
+    // listed in CSV file.
+    let tracks: Vec<&db::TrackAnalysis> = all_tracks.iter().filter(|t| if csv { !t.ignore } else { playlist::playable(t) }).collect();
+    if tracks.len() < k {
+        return Err(Error::Config(format!("Cannot create {} cluster(s) from {} track(s)", k, tracks.len())));
+    }
+
+    log::info!("Grouping {} track(s) into {} cluster(s)", tracks.len(), k);
+    let assignments = kmeans(&tracks, k);
+
+    let out_path = PathBuf::from(out);
+    if let Err(e) = fs::create_dir_all(&out_path) {
+        return Err(Error::Config(format!("Failed to create output folder ({}). {}", out, e)));
+    }
+    for cluster in 0..k {
+        let files: Vec<&str> = tracks.iter().zip(assignments.iter()).filter(|(_, c)| **c == cluster).map(|(t, _)| t.file.as_str()).collect();
+        log::info!("Cluster {}: {} track(s)", cluster + 1, files.len());
+        if !csv {
+            playlist::write_m3u(&out_path.join(format!("cluster_{:02}.m3u", cluster + 1)), mpaths, &files)?;
+        }
+    }
+    if csv {
+        write_csv(&out_path.join(CSV_FILE), &tracks, &assignments)?;
+    }
+    log::info!("Clusters written to {}", out);
+    Ok(())
+}
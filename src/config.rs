@@ -0,0 +1,74 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use configparser::ini::Ini;
+use std::fs;
+use toml::Value;
+
+// Items within [lms] and [notify] TOML sections, and the INI keys they map to
+const LMS_KEYS: [(&str, &str); 6] = [("host", "lms"), ("json_port", "json"), ("scheme", "lms_scheme"), ("path", "lms_path"), ("user", "lms_user"), ("pass", "lms_pass")];
+const NOTIFY_KEYS: [(&str, &str); 3] = [("url", "notify_url"), ("interval", "notify_interval"), ("failures", "notify_failures")];
+
+fn value_str(key: &str, val: &Value) -> Result<String, String> {
+    match val {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(i) => Ok(i.to_string()),
+        Value::Float(f) => Ok(f.to_string()),
+        Value::Boolean(b) => Ok(b.to_string()),
+        _ => Err(format!("Invalid value for '{}'", key)),
+    }
+}
+
+// Set INI key from TOML value. Arrays are stored as key, key_1, key_2, etc. - as
+// used for repeatable INI items.
+fn set(ini: &mut Ini, section: &str, key: &str, val: &Value) -> Result<(), String> {
+    match val {
+        Value::Array(vals) => {
+            for (i, v) in vals.iter().enumerate() {
+                let name = if i == 0 { key.to_string() } else { format!("{}_{}", key, i) };
+                ini.set(section, &name, Some(value_str(key, v)?));
+            }
+        }
+        _ => { ini.set(section, key, Some(value_str(key, val)?)); }
+    }
+    Ok(())
+}
+
+fn set_table(ini: &mut Ini, section: &str, name: &str, table: &toml::value::Table, keys: &[(&str, &str)]) -> Result<(), String> {
+    for (key, val) in table {
+        match keys.iter().find(|(k, _)| k == key) {
+            Some((_, ini_key)) => { set(ini, section, ini_key, val)?; }
+            None => { return Err(format!("Unknown item '{}' in [{}]", key, name)); }
+        }
+    }
+    Ok(())
+}
+
+/// Load TOML config file, and convert to the INI items used by config files with
+/// 'section'. Top-level items have the same names as INI items, except that music
+/// folders may be given as a 'music_paths' array. LMS and notification settings may
+/// be placed within [lms] and [notify] tables.
+pub fn load_toml(path: &str, section: &str) -> Result<Ini, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let root = match contents.parse::<Value>() {
+        Ok(Value::Table(table)) => table,
+        Ok(_) => { return Err(String::from("Invalid TOML")); }
+        Err(e) => { return Err(e.to_string()); }
+    };
+    let mut ini = Ini::new();
+    for (key, val) in &root {
+        match (key.as_str(), val) {
+            ("lms", Value::Table(table)) => { set_table(&mut ini, section, key, table, &LMS_KEYS)?; }
+            ("notify", Value::Table(table)) => { set_table(&mut ini, section, key, table, &NOTIFY_KEYS)?; }
+            (_, Value::Table(_)) => { return Err(format!("Unknown section [{}]", key)); }
+            ("music_paths", _) => { set(&mut ini, section, "music", val)?; }
+            _ => { set(&mut ini, section, key, val)?; }
+        }
+    }
+    Ok(ini)
+}
@@ -0,0 +1,483 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use encoding_rs::{Encoding, WINDOWS_1251, WINDOWS_1252};
+use lofty::{Accessor, ItemKey, TaggedFileExt};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+
+const CUESHEET_TAG: &str = "CUESHEET";
+const FLAC_MARKER: &[u8; 4] = b"fLaC";
+const FLAC_STREAMINFO: u8 = 0;
+const FLAC_CUESHEET: u8 = 5;
+const CD_LEAD_OUT_TRACK: u8 = 170;
+const LEAD_OUT_TRACK: u8 = 255;
+const FRAMES_PER_SEC: u64 = 75;
+/// Track number given to the pregaps of rewritten cue sheets (see 'split_pregaps'),
+/// tracks with this number should not be stored.
+pub const PREGAP_TRACK: i32 = 0;
+
+fn quote(val: &str) -> String {
+    val.replace("\"", "'")
+}
+
+fn cue_time(samples: u64, sample_rate: u64) -> String {
+    let frames = samples * FRAMES_PER_SEC / sample_rate;
+    format!("{:02}:{:02}:{:02}", frames / (FRAMES_PER_SEC * 60), (frames / FRAMES_PER_SEC) % 60, frames % FRAMES_PER_SEC)
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(buf.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+// Parse native FLAC CUESHEET metadata block, and convert this into cue sheet text.
+fn read_flac_cuesheet_block(audio: &Path) -> Option<String> {
+    let mut reader = BufReader::new(File::open(audio).ok()?);
+    let mut marker = [0u8; 4];
+    reader.read_exact(&mut marker).ok()?;
+    if &marker != FLAC_MARKER {
+        return None;
+    }
+
+    let mut sample_rate: u64 = 0;
+    loop {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header).ok()?;
+        let last = (header[0] & 0x80) != 0;
+        let block_type = header[0] & 0x7F;
+        let len = ((header[1] as usize) << 16) | ((header[2] as usize) << 8) | (header[3] as usize);
+        let mut block = vec![0u8; len];
+        reader.read_exact(&mut block).ok()?;
+
+        if block_type == FLAC_STREAMINFO && len >= 13 {
+            sample_rate = ((block[10] as u64) << 12) | ((block[11] as u64) << 4) | ((block[12] as u64) >> 4);
+        } else if block_type == FLAC_CUESHEET && sample_rate > 0 {
+            // catalog(128) + lead-in(8) + flags/reserved(259) + num tracks(1)
+            let mut pos: usize = 395;
+            let num_tracks = *block.get(pos)?;
+            let mut cue = String::new();
+            pos += 1;
+            for _ in 0..num_tracks {
+                let offset = read_u64(&block, pos)?;
+                let track_num = *block.get(pos + 8)?;
+                // offset(8) + number(1) + ISRC(12) + flags/reserved(14)
+                let num_indices = *block.get(pos + 35)?;
+                pos += 36;
+                let lead_out = track_num == CD_LEAD_OUT_TRACK || track_num == LEAD_OUT_TRACK;
+                if !lead_out {
+                    cue.push_str(&format!("  TRACK {:02} AUDIO\n", track_num));
+                }
+                for _ in 0..num_indices {
+                    let idx_offset = read_u64(&block, pos)?;
+                    let idx_num = *block.get(pos + 8)?;
+                    pos += 12;
+                    if !lead_out {
+                        cue.push_str(&format!("    INDEX {:02} {}\n", idx_num, cue_time(offset + idx_offset, sample_rate)));
+                    }
+                }
+            }
+            return if cue.is_empty() { None } else { Some(cue) };
+        }
+
+        if last {
+            return None;
+        }
+    }
+}
+
+/// Read cue sheet embedded within a FLAC file, either as a CUESHEET Vorbis comment
+/// or as a native CUESHEET metadata block. Returned text has its FILE entry set to
+/// the absolute path of the audio file.
+pub fn read_embedded(audio: &Path) -> Option<String> {
+    let file = lofty::read_from_path(audio).ok()?;
+    if !file.file_type().eq(&lofty::FileType::Flac) {
+        return None;
+    }
+    let tag = match file.primary_tag() {
+        Some(primary_tag) => Some(primary_tag),
+        None => file.first_tag(),
+    };
+
+    let mut header = String::new();
+    let mut tracks = String::new();
+    let path = audio.to_string_lossy();
+    if path.contains('"') {
+        // Cannot be written to FILE entry, so analyse as a single track
+        log::warn!("Ignoring embedded cue sheet of '{}', path contains '\"'", path);
+        return None;
+    }
+    let file_line = format!("FILE \"{}\" WAVE\n", path);
+
+    if let Some(tag) = tag {
+        if let Some(sheet) = tag.get_string(&ItemKey::Unknown(CUESHEET_TAG.to_string())) {
+            // Replace any FILE entries with the path of the FLAC itself
+            let mut cue = String::new();
+            let mut have_file = false;
+            for line in sheet.lines() {
+                if line.trim_start().starts_with("FILE ") {
+                    cue.push_str(&file_line);
+                    have_file = true;
+                } else {
+                    cue.push_str(line);
+                    cue.push('\n');
+                }
+            }
+            if !have_file {
+                cue = format!("{}{}", file_line, cue);
+            }
+            return Some(split_pregaps(&cue).unwrap_or(cue));
+        }
+
+        let performer = match tag.get_string(&ItemKey::AlbumArtist) {
+            Some(album_artist) => Some(album_artist.to_string()),
+            None => tag.artist().map(|a| a.to_string()),
+        };
+        if let Some(performer) = performer {
+            header.push_str(&format!("PERFORMER \"{}\"\n", quote(&performer)));
+        }
+        if let Some(album) = tag.album() {
+            header.push_str(&format!("TITLE \"{}\"\n", quote(&album)));
+        }
+    }
+
+    tracks.push_str(&read_flac_cuesheet_block(audio)?);
+    let cue = format!("{}{}{}", header, file_line, tracks);
+    Some(split_pregaps(&cue).unwrap_or(cue))
+}
+
+/// Calculate hash of cue file contents, used to detect when it has been edited.
+pub fn hash(cue: &Path) -> Option<String> {
+    let data = fs::read(cue).ok()?;
+    Some(format!("{:x}", Sha256::digest(&data)))
+}
+
+// Guess legacy 8-bit encoding of cue text. Both Cyrillic and accented Western European
+// letters are bytes above 0xBF, but Cyrillic words are made up entirely of these -
+// whereas accented letters are usually surrounded by ASCII letters. Cue keywords are
+// ASCII, so only these bytes are considered.
+fn guess_legacy_encoding(data: &[u8]) -> &'static Encoding {
+    let mut high: usize = 0;
+    let mut in_words: usize = 0;
+    let mut run: usize = 0;
+    for b in data.iter().chain(std::iter::once(&0)) {
+        if *b >= 0xC0 {
+            run += 1;
+        } else {
+            high += run;
+            if run >= 3 {
+                in_words += run;
+            }
+            run = 0;
+        }
+    }
+    if high > 0 && in_words * 2 > high {
+        WINDOWS_1251
+    } else {
+        WINDOWS_1252
+    }
+}
+
+// Split FILE line into file name and type
+fn file_line_entry(line: &str) -> (&str, &str) {
+    let entry = line.trim_start()[4..].trim();
+    if entry.starts_with('"') {
+        match entry[1..].find('"') {
+            Some(end) => (&entry[1..end + 1], entry[end + 2..].trim()),
+            None => (&entry[1..], ""),
+        }
+    } else {
+        match entry.rfind(char::is_whitespace) {
+            Some(pos) => (entry[..pos].trim(), entry[pos..].trim()),
+            None => (entry, ""),
+        }
+    }
+}
+
+// Convert FILE entry to use absolute path, so that cue can be read from elsewhere.
+// Returns None if path contains '"', as this cannot be written to the entry.
+fn absolute_file_line(line: &str, dir: &Path) -> Option<String> {
+    let (name, file_type) = file_line_entry(line);
+    let path = dir.join(name);
+    let path = path.to_string_lossy();
+    if path.contains('"') {
+        return None;
+    }
+    Some(format!("FILE \"{}\" {}", path, if file_type.is_empty() { "WAVE" } else { file_type }))
+}
+
+// bliss slices each track from its first INDEX up to the first INDEX of the next
+// track. Where a track has both a pregap (INDEX 00) and a start (INDEX 01), the pregap
+// is moved into a track of its own (numbered PREGAP_TRACK) - so that the previous
+// track ends at the pregap, and the track itself starts at INDEX 01. The pregap of
+// the first track of a file is simply removed. Returns None if there were no pregaps.
+fn split_pregaps(text: &str) -> Option<String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut resp = String::new();
+    let mut split = false;
+    let mut first_in_file = true;
+    let mut pos = 0;
+    while pos < lines.len() {
+        let mut end = pos + 1;
+        let is_track = lines[pos].trim_start().starts_with("TRACK ");
+        if is_track {
+            while end < lines.len() && !lines[end].trim_start().starts_with("TRACK ") && !lines[end].trim_start().starts_with("FILE ") {
+                end += 1;
+            }
+        } else if lines[pos].trim_start().starts_with("FILE ") {
+            first_in_file = true;
+        }
+        let block = &lines[pos..end];
+        let has_start = block.iter().any(|l| l.trim_start().starts_with("INDEX 01 "));
+        let pregap = block.iter().find(|l| l.trim_start().starts_with("INDEX 00 "));
+        if let (true, Some(pregap)) = (has_start, pregap) {
+            split = true;
+            if !first_in_file {
+                let indent = &lines[pos][..lines[pos].len() - lines[pos].trim_start().len()];
+                resp.push_str(&format!("{}TRACK {:02} AUDIO\n", indent, PREGAP_TRACK));
+                resp.push_str(&pregap.replacen("INDEX 00 ", "INDEX 01 ", 1));
+                resp.push('\n');
+            }
+        }
+        for line in block {
+            if !(has_start && line.trim_start().starts_with("INDEX 00 ")) {
+                resp.push_str(line);
+                resp.push('\n');
+            }
+        }
+        if is_track {
+            first_in_file = false;
+        }
+        pos = end;
+    }
+    if split { Some(resp) } else { None }
+}
+
+/// Year from 'REM DATE' line of cue file, if any. Dates may be a full date (e.g.
+/// 2001-05-21), so only the leading year is used.
+pub fn year(cue: &Path) -> Option<u32> {
+    let data = fs::read(cue).ok()?;
+    let text = String::from_utf8_lossy(&data);
+    let date = text.lines().map(|l| l.trim()).find_map(|l| l.strip_prefix("REM DATE "))?;
+    let digits: String = date.trim().trim_matches('"').chars().take_while(|c| c.is_ascii_digit()).collect();
+    match digits.len() {
+        4 => digits.parse::<u32>().ok(),
+        _ => None,
+    }
+}
+
+/// Cue files that are not UTF-8 are transcoded into a temporary UTF-8 copy, as the
+/// cue parser requires UTF-8. Likewise, cue files with pregaps are rewritten so that
+/// tracks start at INDEX 01, and end at the next track's pregap. Returns None if cue
+/// can be used as-is, or cannot be rewritten.
+pub fn prepare(cue: &Path, index: usize) -> Option<PathBuf> {
+    let data = fs::read(cue).ok()?;
+    let encoding = match Encoding::for_bom(&data) {
+        Some((encoding, _)) => Some(encoding),
+        None => {
+            if std::str::from_utf8(&data).is_ok() {
+                None
+            } else {
+                Some(guess_legacy_encoding(&data))
+            }
+        }
+    };
+    let text = match encoding {
+        Some(encoding) => {
+            log::debug!("Assuming '{}' is encoded as {}", cue.to_string_lossy(), encoding.name());
+            encoding.decode(&data).0
+        }
+        None => String::from_utf8_lossy(&data),
+    };
+    let split = split_pregaps(&text);
+    if encoding.is_none() && split.is_none() {
+        return None;
+    }
+    let text = split.unwrap_or(text.to_string());
+    let dir = cue.parent()?;
+    let mut utf8 = String::new();
+    for line in text.lines() {
+        if line.trim_start().starts_with("FILE ") {
+            match absolute_file_line(line, dir) {
+                Some(file_line) => { utf8.push_str(&file_line); }
+                None => {
+                    log::warn!("Cannot rewrite '{}', audio path contains '\"'", cue.to_string_lossy());
+                    return None;
+                }
+            }
+        } else {
+            utf8.push_str(line);
+        }
+        utf8.push('\n');
+    }
+    write_temp(&utf8, cue, index)
+}
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("bliss-analyser-{}", process::id()))
+}
+
+/// Write cue sheet to a temporary file, so that it can be analysed in the same
+/// manner as a standalone CUE file.
+pub fn write_temp(cue: &str, source: &Path, index: usize) -> Option<PathBuf> {
+    let dir = temp_dir();
+    if let Err(e) = fs::create_dir_all(&dir) {
+        log::error!("Failed to create temporary folder for embedded cue sheets. {}", e);
+        return None;
+    }
+    let name = source.file_name().unwrap_or_default().to_string_lossy();
+    let path = dir.join(format!("{}-{}.cue", index, name));
+    match fs::write(&path, cue) {
+        Ok(_) => Some(path),
+        Err(e) => {
+            log::error!("Failed to write temporary cue sheet for '{}'. {}", source.to_string_lossy(), e);
+            None
+        }
+    }
+}
+
+/// Total size of the audio files referenced by a cue file.
+pub fn audio_size(cue: &Path) -> u64 {
+    let dir = match cue.parent() {
+        Some(dir) => dir,
+        None => { return 0; }
+    };
+    match fs::read(cue) {
+        Ok(data) => {
+            String::from_utf8_lossy(&data).lines()
+                .filter(|line| line.trim_start().starts_with("FILE "))
+                .filter_map(|line| fs::metadata(dir.join(file_line_entry(line).0)).ok())
+                .map(|meta| meta.len())
+                .sum()
+        }
+        Err(_) => 0,
+    }
+}
+
+/// Remove any temporary cue files.
+pub fn remove_temp() {
+    let dir = temp_dir();
+    if dir.exists() {
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Start of track, and its duration (None for last track), in frames - sliced as
+    // bliss does, i.e. from first INDEX up to the first INDEX of the next track.
+    fn slices(cue: &str) -> Vec<(String, u64, Option<u64>)> {
+        let frames = |time: &str| -> u64 {
+            let parts: Vec<u64> = time.split(':').map(|p| p.parse().unwrap()).collect();
+            (parts[0] * 60 + parts[1]) * FRAMES_PER_SEC + parts[2]
+        };
+        let mut starts: Vec<(String, u64)> = Vec::new();
+        let mut have_index = false;
+        for line in cue.lines().map(|l| l.trim()) {
+            if let Some(track) = line.strip_prefix("TRACK ") {
+                starts.push((track.split_whitespace().next().unwrap().to_string(), 0));
+                have_index = false;
+            } else if let Some(index) = line.strip_prefix("INDEX ") {
+                if !have_index {
+                    starts.last_mut().unwrap().1 = frames(index.split_whitespace().nth(1).unwrap());
+                    have_index = true;
+                }
+            }
+        }
+        starts.iter().enumerate()
+            .map(|(i, (num, start))| (num.clone(), *start, starts.get(i + 1).map(|next| next.1 - start)))
+            .collect()
+    }
+
+    #[test]
+    fn pregaps_end_previous_track() {
+        let cue = [
+            "FILE \"album.flac\" WAVE",
+            "  TRACK 01 AUDIO",
+            "    INDEX 00 00:00:00",
+            "    INDEX 01 00:00:33",
+            "  TRACK 02 AUDIO",
+            "    TITLE \"Two\"",
+            "    INDEX 00 03:10:00",
+            "    INDEX 01 03:12:00",
+            "  TRACK 03 AUDIO",
+            "    INDEX 01 06:00:00",
+        ].join("\n");
+        let split = split_pregaps(&cue).unwrap();
+        let tracks: Vec<(String, u64, Option<u64>)> = slices(&split).into_iter()
+            .filter(|(num, _, _)| num.parse::<i32>() != Ok(PREGAP_TRACK))
+            .collect();
+        assert_eq!(tracks, vec![
+            (String::from("01"), 33, Some(190 * FRAMES_PER_SEC - 33)),
+            (String::from("02"), 192 * FRAMES_PER_SEC, Some(168 * FRAMES_PER_SEC)),
+            (String::from("03"), 360 * FRAMES_PER_SEC, None),
+        ]);
+        assert!(split.contains("    TITLE \"Two\"\n"));
+    }
+
+    #[test]
+    fn no_pregaps() {
+        let cue = "FILE \"album.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 01 03:00:00\n";
+        assert_eq!(split_pregaps(cue), None);
+    }
+
+    // Write 'data' as a cue file, and return UTF-8 text of prepared copy - if any.
+    fn prepare_fixture(name: &str, data: &[u8]) -> Option<String> {
+        let dir = std::env::temp_dir().join(format!("bliss-analyser-test-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let cue = dir.join(format!("{}.cue", name));
+        fs::write(&cue, data).unwrap();
+        let prepared = prepare(&cue, 0);
+        let text = prepared.as_ref().map(|p| fs::read_to_string(p).unwrap());
+        if let Some(path) = prepared {
+            let _ = fs::remove_file(path);
+        }
+        let _ = fs::remove_dir_all(&dir);
+        text
+    }
+
+    const FIXTURE: &str = "PERFORMER \"Björk\"\nTITLE \"Début\"\nFILE \"album.flac\" WAVE\n  TRACK 01 AUDIO\n    TITLE \"Human Behaviour\"\n    INDEX 01 00:00:00\n";
+
+    #[test]
+    fn utf8_cue_used_as_is() {
+        assert_eq!(prepare_fixture("utf8", FIXTURE.as_bytes()), None);
+    }
+
+    #[test]
+    fn latin1_cue() {
+        let text = prepare_fixture("latin1", &WINDOWS_1252.encode(FIXTURE).0).unwrap();
+        assert!(text.contains("PERFORMER \"Björk\"\nTITLE \"Début\"\n"));
+        assert!(text.contains("/album.flac\" WAVE\n"));
+    }
+
+    #[test]
+    fn utf16le_cue() {
+        let mut data: Vec<u8> = vec![0xFF, 0xFE];
+        data.extend(FIXTURE.encode_utf16().flat_map(|c| c.to_le_bytes()));
+        let text = prepare_fixture("utf16le", &data).unwrap();
+        assert!(text.contains("PERFORMER \"Björk\"\nTITLE \"Début\"\n"));
+    }
+
+    #[test]
+    fn cyrillic_cue() {
+        let cyrillic = "PERFORMER \"Кино\"\nTITLE \"Группа крови\"\nFILE \"album.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n";
+        let text = prepare_fixture("cp1251", &WINDOWS_1251.encode(cyrillic).0).unwrap();
+        assert!(text.contains("PERFORMER \"Кино\"\nTITLE \"Группа крови\"\n"));
+    }
+
+    #[test]
+    fn quote_in_audio_path() {
+        assert_eq!(absolute_file_line("FILE \"album.flac\" WAVE", Path::new("/music/12\" Mixes")), None);
+        assert_eq!(absolute_file_line("FILE \"album.flac\" WAVE", Path::new("/music/Album")), Some(String::from("FILE \"/music/Album/album.flac\" WAVE")));
+    }
+}
@@ -0,0 +1,70 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::analyse;
+use crate::error::Error;
+use crate::notify;
+use chrono::Local;
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STATE_EXT: &str = "state.json";
+
+/// Parse interval such as '90s', '30m', '6h', or '1d'. A plain number is in seconds.
+pub fn parse_interval(val: &str) -> Option<u64> {
+    let val = val.trim();
+    let (num, mult) = match val.chars().last()? {
+        's' | 'S' => (&val[..val.len() - 1], 1),
+        'm' | 'M' => (&val[..val.len() - 1], 60),
+        'h' | 'H' => (&val[..val.len() - 1], 60 * 60),
+        'd' | 'D' => (&val[..val.len() - 1], 24 * 60 * 60),
+        _ => (val, 1),
+    };
+    match num.trim().parse::<u64>() {
+        Ok(n) if n > 0 => Some(n * mult),
+        _ => None,
+    }
+}
+
+fn with_ext(db_path: &str, ext: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}", db_path, ext))
+}
+
+fn write_state(path: &PathBuf, summary: &analyse::Summary) {
+    let state = json!({
+        "last_run": Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        "files_analysed": summary.analysed,
+        "failures": summary.failed,
+        "tag_errors": summary.tag_errors,
+    });
+    if let Err(e) = fs::write(path, state.to_string()) {
+        log::error!("Failed to write state file ({}). {}", path.to_string_lossy(), e);
+    }
+}
+
+/// Analyse new files every 'interval' seconds, until stopped via Ctrl-C or SIGTERM.
+/// After each run, details are written to a state file next to the DB.
+pub fn run(db_path: &str, mpaths: &Vec<PathBuf>, opts: &analyse::Options, notifier: &mut notify::Notifier, interval: u64) -> Result<(), Error> {
+    let state_path = with_ext(db_path, STATE_EXT);
+    while !analyse::terminated() {
+        let start = Instant::now();
+        let summary = analyse::analyse_files(db_path, mpaths, opts, notifier)?;
+        write_state(&state_path, &summary);
+        if analyse::terminated() {
+            break;
+        }
+        log::info!("Next analysis in {} second(s)", interval.saturating_sub(start.elapsed().as_secs()));
+        while !analyse::terminated() && start.elapsed() < Duration::from_secs(interval) {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+    Ok(())
+}
@@ -6,15 +6,212 @@
  *
  **/
 
+use crate::error::Error;
+use crate::report;
 use crate::tags;
 use bliss_audio::{Analysis, AnalysisIndex};
+use chrono::Local;
+use globset::GlobBuilder;
 use indicatif::{ProgressBar, ProgressStyle};
-use rusqlite::{params, Connection};
+use regex::Regex;
+use rusqlite::{params, Connection, DatabaseName};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::path::PathBuf;
-use std::process;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use unicode_normalization::UnicodeNormalization;
 
 pub const CUE_MARKER: &str = ".CUE_TRACK.";
+pub const DB_VERSION: u32 = 7;
+pub const DEFAULT_BACKUPS: usize = 3;
+pub const DEFAULT_MAX_REMOVE_PERCENT: usize = 50;
+// Number of non-existent tracks to list when refusing to remove these
+const MAX_MISSING_LISTED: usize = 5;
+const BACKUP_EXT: &str = ".bak";
+pub const INFO_ANALYSER_VERSION: &str = "AnalyserVersion";
+pub const INFO_FEATURES_VERSION: &str = "FeaturesVersion";
+// Decoder used to analyse tracks, stored with each track as results can differ slightly
+// between decoders.
+pub const DECODER: &str = "ffmpeg";
+// Analysis values are normalised to -1..1, so anything well outside this is invalid
+const MAX_ANALYSIS_VALUE: f64 = 1.5;
+pub const ANALYSIS_COLUMNS: &str = "Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10";
+const NUM_ANALYSIS_COLUMNS: usize = 20;
+
+// Store paths in NFD form, rather than NFC
+static NFD_PATHS: AtomicBool = AtomicBool::new(false);
+
+/// Set Unicode form of paths stored in DB, must be called before DB is opened.
+pub fn set_nfd_paths(nfd: bool) {
+    NFD_PATHS.store(nfd, Ordering::Relaxed);
+}
+
+// Number of backups to keep, 0 to never backup
+static NUM_BACKUPS: AtomicUsize = AtomicUsize::new(DEFAULT_BACKUPS);
+
+/// Set number of backups, made before tracks are removed or DB is upgraded, to keep.
+pub fn set_num_backups(num: usize) {
+    NUM_BACKUPS.store(num, Ordering::Relaxed);
+}
+
+// Remove oldest backups of 'db_path', so that only 'keep' remain. Backup names contain
+// a timestamp, so sorting by name sorts by age.
+fn remove_old_backups(db_path: &Path, keep: usize) {
+    let dir = match db_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.", db_path.file_name().unwrap_or_default().to_string_lossy());
+    let mut backups: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.flatten()
+            .filter(|e| e.file_name().to_str().map_or(false, |n| n.starts_with(&prefix) && n.ends_with(BACKUP_EXT)))
+            .map(|e| e.path())
+            .collect(),
+        Err(_) => { return; }
+    };
+    if backups.len() <= keep {
+        return;
+    }
+    backups.sort();
+    for old in &backups[..backups.len() - keep] {
+        match fs::remove_file(old) {
+            Ok(_) => { log::debug!("Removed old backup '{}'", old.to_string_lossy()); }
+            Err(e) => { log::warn!("Failed to remove old backup '{}'. {}", old.to_string_lossy(), e); }
+        }
+    }
+}
+
+/// Path as stored in DB, i.e. always using '/' as separator, and with Unicode
+/// characters in NFC (or, if configured, NFD) form - so that paths read on macOS
+/// (NFD) and Linux match.
+pub fn normalise(path: &str) -> String {
+    let path: String = if NFD_PATHS.load(Ordering::Relaxed) { path.nfd().collect() } else { path.nfc().collect() };
+    if cfg!(windows) {
+        db_separators(&path)
+    } else {
+        path
+    }
+}
+
+// Windows path using '/' as separator, as stored in DB.
+fn db_separators(path: &str) -> String {
+    path.replace("\\", "/")
+}
+
+// DB path using Windows '\' separator, reverse of db_separators().
+fn windows_separators(path: &str) -> String {
+    path.replace("/", "\\")
+}
+
+/// Whether DB path 'file' is 'path', one of its CUE tracks, or is within the folder
+/// 'path'. This is the same as PATH_MATCH.
+pub fn path_matches(file: &str, path: &str) -> bool {
+    let m = path_match(path);
+    file == m[0] || file.starts_with(&m[1]) || file.starts_with(&m[2])
+}
+
+// Tracks that have not been analysed (see add_unanalysed()) have no analysis values,
+// so must remain ignored when un-ignoring tracks. Every update setting Ignore=0 must
+// include this condition.
+const UNIGNORE_GUARD: &str = "Tempo IS NOT NULL";
+
+// Match File against the parameters created by path_match()
+const PATH_MATCH: &str = "(File=? OR instr(File, ?)=1 OR instr(File, ?)=1)";
+
+// Parameters for PATH_MATCH, matching the file 'path', the tracks of a CUE file, or the
+// contents of a folder. Unlike LIKE, instr() is case-sensitive and has no wildcards, and
+// folders only match whole names - e.g. 'Rock' does not match 'Rockabilly/...'.
+fn path_match(path: &str) -> [String; 3] {
+    let path = normalise(path);
+    let folder = if path.ends_with('/') { path.clone() } else { format!("{}/", path) };
+    [path.clone(), format!("{}{}", path, CUE_MARKER), folder]
+}
+
+/// Path using platform's separator, i.e. reverse of the separator change made by
+/// normalise(). Paths with a '\\?\' prefix (see long_path()) cannot use '/'.
+pub fn native(path: &str) -> String {
+    if cfg!(windows) {
+        windows_separators(path)
+    } else {
+        path.to_string()
+    }
+}
+
+/// Windows limits paths to 260 characters, unless they have a '\\?\' prefix (or
+/// '\\?\UNC\' for network shares). Music folders are converted to this form, so that
+/// all paths within them may be longer. Other platforms are unchanged.
+pub fn long_path(path: &Path) -> PathBuf {
+    let spath = path.to_string_lossy();
+    if !cfg!(windows) || !path.is_absolute() || spath.starts_with("\\\\?\\") {
+        return path.to_path_buf();
+    }
+    PathBuf::from(windows_long_path(&native(&spath)))
+}
+
+// Absolute Windows path, with '\' separators, in '\\?\' form.
+fn windows_long_path(path: &str) -> String {
+    match path.strip_prefix("\\\\") {
+        Some(unc) => format!("\\\\?\\UNC\\{}", unc),
+        None => format!("\\\\?\\{}", path),
+    }
+}
+
+/// Location of DB file within a music folder. If the file does not exist, check for
+/// the other Unicode form - e.g. DB is NFC, but macOS wrote file as NFD.
+pub fn fs_path(mpath: &Path, file: &str) -> PathBuf {
+    let file = native(file);
+    let path = mpath.join(&file);
+    if !file.is_ascii() && !path.exists() {
+        let other: String = if NFD_PATHS.load(Ordering::Relaxed) { file.nfc().collect() } else { file.nfd().collect() };
+        let other = mpath.join(other);
+        if other.exists() {
+            return other;
+        }
+    }
+    path
+}
+
+// Find files, within 'dir', whose (normalised) name is in 'names'. Found files are stored
+// against their name, as absolute path and path relative to 'mpath'. Symlinked folders
+// are not followed.
+fn find_named_files(mpath: &Path, dir: &Path, names: &HashSet<String>, found: &mut HashMap<String, Vec<(PathBuf, String)>>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => { return; }
+    };
+    for entry in entries.flatten() {
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => { continue; }
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            find_named_files(mpath, &path, names, found);
+        } else if file_type.is_file() {
+            let name = normalise(&entry.file_name().to_string_lossy());
+            if names.contains(&name) {
+                if let Ok(stripped) = path.strip_prefix(mpath) {
+                    let sname = normalise(&stripped.to_string_lossy());
+                    let files = found.entry(name).or_default();
+                    if !files.iter().any(|(_, f)| *f == sname) {
+                        files.push((path, sname));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Path of audio file, for tracks of a CUE file this is the path without the track suffix
+fn audio_path(path: &str) -> &str {
+    match path.find(CUE_MARKER) {
+        Some(s) => &path[..s],
+        None => path,
+    }
+}
 
 pub struct FileMetadata {
     pub rowid: usize,
@@ -25,6 +222,16 @@ pub struct FileMetadata {
     pub album: Option<String>,
     pub genre: Option<String>,
     pub duration: u32,
+    pub track_num: Option<u32>,
+    pub disc: Option<u32>,
+    pub year: Option<u32>,
+    pub size: Option<i64>,
+}
+
+pub struct TrackAnalysis {
+    pub file: String,
+    pub ignore: bool,
+    pub analysis: Vec<f64>,
 }
 
 #[derive(Default, PartialEq)]
@@ -35,6 +242,11 @@ pub struct Metadata {
     pub album: String,
     pub genre: String,
     pub duration: u32,
+    pub track_num: Option<u32>,
+    pub disc: Option<u32>,
+    pub year: Option<u32>,
+    // Size of file, in bytes, used to find files that have moved
+    pub size: Option<i64>,
 }
 
 impl Metadata {
@@ -49,24 +261,28 @@ impl Metadata {
 
 pub struct Db {
     pub conn: Connection,
+    path: String,
+    // Only backup once per connection, e.g. not for each path removed
+    backed_up: Cell<bool>,
 }
 
 impl Db {
-    pub fn new(path: &String) -> Self {
+    pub fn new(path: &String) -> Result<Self, Error> {
         match Connection::open(path) {
             Ok(conn) => {
-                Self {
+                Ok(Self {
                     conn: conn,
-                }
+                    path: path.clone(),
+                    backed_up: Cell::new(false),
+                })
             }
             Err(e) => {
-                log::error!("Failed top open/create database. {}", e);
-                process::exit(-1);
+                Err(Error::Db(format!("Failed top open/create database. {}", e)))
             }
         }
     }
 
-    pub fn init(&self) {
+    pub fn init(&self) -> Result<(), Error> {
         let cmd = self.conn.execute(
             "CREATE TABLE IF NOT EXISTS Tracks (
                 File text primary key,
@@ -76,6 +292,9 @@ impl Db {
                 AlbumArtist text,
                 Genre text,
                 Duration integer,
+                TrackNum integer,
+                Disc integer,
+                Year integer,
                 Ignore integer,
                 Tempo real,
                 Zcr real,
@@ -96,21 +315,254 @@ impl Db {
                 Chroma7 real,
                 Chroma8 real,
                 Chroma9 real,
-                Chroma10 real
+                Chroma10 real,
+                AnalysisVersion integer,
+                Decoder text,
+                Analysed integer,
+                Size integer
             );",
             [],
         );
 
-        if cmd.is_err() {
-            log::error!("Failed to create DB table");
-            process::exit(-1);
+        if let Err(e) = cmd {
+            return Err(Error::Db(format!("Failed to create DB table. {}", e)));
         }
 
         let cmd = self.conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS Tracks_idx ON Tracks(File)", []);
 
-        if cmd.is_err() {
-            log::error!("Failed to create DB index");
-            process::exit(-1);
+        if let Err(e) = cmd {
+            return Err(Error::Db(format!("Failed to create DB index. {}", e)));
+        }
+
+        let cmd = self.conn.execute("CREATE TABLE IF NOT EXISTS CueFiles (File text primary key, Hash text);", []);
+
+        if let Err(e) = cmd {
+            return Err(Error::Db(format!("Failed to create DB table. {}", e)));
+        }
+
+        let cmd = self.conn.execute("CREATE TABLE IF NOT EXISTS Failures (File text primary key, Error text, LastTried integer);", []);
+
+        if let Err(e) = cmd {
+            return Err(Error::Db(format!("Failed to create DB table. {}", e)));
+        }
+
+        let cmd = self.conn.execute("CREATE TABLE IF NOT EXISTS Info (Key text primary key, Value text);", []);
+
+        if let Err(e) = cmd {
+            return Err(Error::Db(format!("Failed to create DB table. {}", e)));
+        }
+
+        if self.get_version() < DB_VERSION && self.get_track_count() > 0 && !self.backup("upgrading database") {
+            return Err(Error::Db(String::from("Failed to backup database before upgrading it")));
+        }
+
+        if self.get_version() < 2 {
+            self.add_analysis_version()?;
+        }
+
+        if self.get_version() < 3 {
+            self.normalise_paths()?;
+        }
+
+        if self.get_version() < 4 {
+            self.add_track_info()?;
+        }
+
+        if self.get_version() < 5 {
+            self.add_decoder()?;
+        }
+
+        if self.get_version() < 6 {
+            self.add_analysed()?;
+        }
+
+        if self.get_version() < 7 {
+            self.add_size()?;
+        }
+
+        if self.get_version() < DB_VERSION {
+            let cmd = self.conn.execute(&format!("PRAGMA user_version = {};", DB_VERSION), []);
+
+            if let Err(e) = cmd {
+                return Err(Error::Db(format!("Failed to set DB version. {}", e)));
+            }
+        }
+        Ok(())
+    }
+
+    // Version 2 added AnalysisVersion column. Existing tracks are assumed to have
+    // been analysed with the features version stored in Info table, if any.
+    fn add_analysis_version(&self) -> Result<(), Error> {
+        if self.conn.prepare("SELECT AnalysisVersion FROM Tracks LIMIT 1;").is_err() {
+            if let Err(e) = self.conn.execute("ALTER TABLE Tracks ADD COLUMN AnalysisVersion integer;", []) {
+                return Err(Error::Db(format!("Failed to add AnalysisVersion column. {}", e)));
+            }
+        }
+        if let Some(version) = self.get_info(INFO_FEATURES_VERSION).and_then(|v| v.parse::<u16>().ok()) {
+            if let Err(e) = self.conn.execute("UPDATE Tracks SET AnalysisVersion=? WHERE AnalysisVersion IS NULL;", params![version]) {
+                return Err(Error::Db(format!("Failed to set AnalysisVersion. {}", e)));
+            }
+        }
+        Ok(())
+    }
+
+    // Version 4 added TrackNum, Disc, and Year columns. These are populated for existing
+    // tracks when they are re-analysed, or their tags are updated.
+    fn add_track_info(&self) -> Result<(), Error> {
+        for column in ["TrackNum", "Disc", "Year"] {
+            if self.conn.prepare(&format!("SELECT {} FROM Tracks LIMIT 1;", column)).is_err() {
+                if let Err(e) = self.conn.execute(&format!("ALTER TABLE Tracks ADD COLUMN {} integer;", column), []) {
+                    return Err(Error::Db(format!("Failed to add {} column. {}", column, e)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Version 5 added Decoder column. This is unknown (NULL) for existing tracks.
+    fn add_decoder(&self) -> Result<(), Error> {
+        if self.conn.prepare("SELECT Decoder FROM Tracks LIMIT 1;").is_err() {
+            if let Err(e) = self.conn.execute("ALTER TABLE Tracks ADD COLUMN Decoder text;", []) {
+                return Err(Error::Db(format!("Failed to add Decoder column. {}", e)));
+            }
+        }
+        Ok(())
+    }
+
+    // Version 6 added Analysed column, time (seconds since epoch) track was analysed. This
+    // is unknown (NULL) for existing tracks.
+    fn add_analysed(&self) -> Result<(), Error> {
+        if self.conn.prepare("SELECT Analysed FROM Tracks LIMIT 1;").is_err() {
+            if let Err(e) = self.conn.execute("ALTER TABLE Tracks ADD COLUMN Analysed integer;", []) {
+                return Err(Error::Db(format!("Failed to add Analysed column. {}", e)));
+            }
+        }
+        Ok(())
+    }
+
+    // Version 7 added Size column, used to find moved files. This is set for existing
+    // tracks when they are re-analysed, or their tags are updated.
+    fn add_size(&self) -> Result<(), Error> {
+        if self.conn.prepare("SELECT Size FROM Tracks LIMIT 1;").is_err() {
+            if let Err(e) = self.conn.execute("ALTER TABLE Tracks ADD COLUMN Size integer;", []) {
+                return Err(Error::Db(format!("Failed to add Size column. {}", e)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert paths to configured Unicode form (version 3 of DB stores paths in a single
+    /// form). If a file is stored in both forms, then the entry already in the configured
+    /// form is kept. Returns number of paths converted.
+    pub fn normalise_paths(&self) -> Result<usize, Error> {
+        let mut count: usize = 0;
+        let tx = match self.conn.unchecked_transaction() {
+            Ok(tx) => tx,
+            Err(e) => { return Err(Error::Db(format!("Failed to normalise paths. {}", e))); }
+        };
+        for table in ["Tracks", "Failures", "CueFiles"] {
+            let files: Vec<String> = match tx.prepare(&format!("SELECT File FROM {};", table)) {
+                Ok(mut stmt) => match stmt.query_map([], |row| row.get(0)) {
+                    Ok(rows) => rows.flatten().collect(),
+                    Err(_) => Vec::new(),
+                },
+                Err(_) => Vec::new(),
+            };
+            for file in files.iter().filter(|f| !f.is_ascii()) {
+                let normalised = normalise(file);
+                if normalised != *file {
+                    let res = tx.execute(&format!("UPDATE OR IGNORE {} SET File=? WHERE File=?;", table), params![normalised, file])
+                        .and_then(|_| tx.execute(&format!("DELETE FROM {} WHERE File=?;", table), params![file]));
+                    if let Err(e) = res {
+                        return Err(Error::Db(format!("Failed to normalise '{}'. {}", file, e)));
+                    }
+                    count += 1;
+                }
+            }
+        }
+        if let Err(e) = tx.commit() {
+            return Err(Error::Db(format!("Failed to normalise paths. {}", e)));
+        }
+        Ok(count)
+    }
+
+    /// Schema version of DB, 0 if created by an older analyser.
+    pub fn get_version(&self) -> u32 {
+        self.conn.query_row("PRAGMA user_version;", [], |row| row.get(0)).unwrap_or(0)
+    }
+
+    /// Record file that failed to be analysed, so that it is not retried on every scan.
+    pub fn add_failure(&self, path: &str, error: &str) {
+        let db_path = normalise(path);
+        let cmd = self.conn.execute("INSERT OR REPLACE INTO Failures (File, Error, LastTried) VALUES (?, ?, strftime('%s','now'));", params![db_path, error]);
+
+        if let Err(e) = cmd {
+            log::error!("Failed to store failure of '{}'. {}", path, e);
+        }
+    }
+
+    pub fn has_failure(&self, path: &str) -> bool {
+        let db_path = normalise(path);
+        self.conn.query_row("SELECT rowid FROM Failures WHERE File=?;", params![db_path], |row| row.get::<_, i64>(0)).is_ok()
+    }
+
+    pub fn remove_failure(&self, path: &str) {
+        let db_path = normalise(path);
+        let _ = self.conn.execute("DELETE FROM Failures WHERE File=?;", params![db_path]);
+    }
+
+    /// Get files that previously failed, and their error.
+    pub fn get_failures(&self) -> Vec<(String, String)> {
+        let mut failures: Vec<(String, String)> = Vec::new();
+        let mut stmt = self.conn.prepare("SELECT File, Error FROM Failures ORDER BY File ASC;").unwrap();
+        let failure_iter = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, Option<String>>(1)?))).unwrap();
+        for failure in failure_iter.flatten() {
+            failures.push((failure.0, failure.1.unwrap_or_default()));
+        }
+        failures
+    }
+
+    /// Remove all failures, so that these files are retried. Returns number removed.
+    pub fn clear_failures(&self) -> usize {
+        match self.conn.execute("DELETE FROM Failures;", []) {
+            Ok(count) => count,
+            Err(e) => {
+                log::error!("Failed to clear failures. {}", e);
+                0
+            }
+        }
+    }
+
+    pub fn get_info(&self, key: &str) -> Option<String> {
+        self.conn.query_row("SELECT Value FROM Info WHERE Key=?;", params![key], |row| row.get(0)).ok()
+    }
+
+    pub fn set_info(&self, key: &str, value: &str) {
+        let cmd = self.conn.execute("INSERT OR REPLACE INTO Info (Key, Value) VALUES (?, ?);", params![key, value]);
+
+        if let Err(e) = cmd {
+            log::error!("Failed to store '{}' in database. {}", key, e);
+        }
+    }
+
+    /// Prepare DB for a dry run. An existing DB whose schema is outdated is not upgraded
+    /// (or backed up), as that would modify it - an error is returned instead. Further
+    /// changes are then rejected.
+    pub fn init_dry_run(&self) -> Result<(), Error> {
+        let exists = self.conn.prepare("SELECT File FROM Tracks LIMIT 1;").is_ok();
+        if exists && self.get_version() < DB_VERSION {
+            return Err(Error::Db(String::from("Database needs upgrading, please run without --dry-run first")));
+        }
+        // New DB, so only empty tables are created
+        self.init()?;
+        self.set_query_only();
+        Ok(())
+    }
+
+    /// Reject any further changes to DB, used for dry runs.
+    pub fn set_query_only(&self) {
+        if let Err(e) = self.conn.execute_batch("PRAGMA query_only = ON;") {
+            log::error!("Failed to set DB as query only. {}", e);
         }
     }
 
@@ -118,11 +570,32 @@ impl Db {
         let _ = self.conn.close();
     }
 
-    pub fn get_rowid(&self, path: &str) -> Result<usize, rusqlite::Error> {
-        let mut db_path = path.to_string();
-        if cfg!(windows) {
-            db_path = db_path.replace("\\", "/");
+    /// Copy DB to '<db>.<timestamp>.bak', using SQLite's backup API so that the copy is
+    /// consistent, prior to 'action' modifying it. Only the configured number of backups
+    /// are kept. Returns false if the backup failed, in which case 'action' should not
+    /// be performed.
+    pub fn backup(&self, action: &str) -> bool {
+        let keep = NUM_BACKUPS.load(Ordering::Relaxed);
+        if keep == 0 || self.backed_up.get() {
+            return true;
+        }
+        let backup_path = format!("{}.{}{}", self.path, Local::now().format("%Y%m%d-%H%M%S-%3f"), BACKUP_EXT);
+        match self.conn.backup(DatabaseName::Main, &backup_path, None) {
+            Ok(_) => {
+                log::info!("Backed up database to '{}' before {}", backup_path, action);
+                self.backed_up.set(true);
+                remove_old_backups(Path::new(&self.path), keep);
+                true
+            }
+            Err(e) => {
+                log::error!("Failed to backup database to '{}' before {}. {}", backup_path, action, e);
+                false
+            }
         }
+    }
+
+    pub fn get_rowid(&self, path: &str) -> Result<usize, rusqlite::Error> {
+        let db_path = normalise(path);
         let mut stmt = self.conn.prepare("SELECT rowid FROM Tracks WHERE File=:path;")?;
         let track_iter = stmt.query_map(&[(":path", &db_path)], |row| Ok(row.get(0)?)).unwrap();
         let mut rowid: usize = 0;
@@ -133,30 +606,40 @@ impl Db {
         Ok(rowid)
     }
 
-    pub fn add_track(&self, path: &String, meta: &Metadata, analysis: &Analysis) {
-        let mut db_path = path.clone();
-        if cfg!(windows) {
-            db_path = db_path.replace("\\", "/");
+    /// Paths of all tracks in DB.
+    pub fn get_all_paths(&self) -> HashSet<String> {
+        let mut paths: HashSet<String> = HashSet::new();
+        if let Ok(mut stmt) = self.conn.prepare("SELECT File FROM Tracks;") {
+            if let Ok(rows) = stmt.query_map([], |row| row.get(0)) {
+                paths.extend(rows.flatten());
+            }
         }
+        paths
+    }
+
+    pub fn add_track(&self, path: &String, meta: &Metadata, analysis: &Analysis) {
+        let db_path = normalise(path);
+        let analysed = Local::now().timestamp();
         match self.get_rowid(&path) {
             Ok(id) => {
                 if id <= 0 {
-                    match self.conn.execute("INSERT INTO Tracks (File, Title, Artist, AlbumArtist, Album, Genre, Duration, Ignore, Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
-                            params![db_path, meta.title, meta.artist, meta.album_artist, meta.album, meta.genre, meta.duration, 0,
+                    match self.conn.execute("INSERT INTO Tracks (File, Title, Artist, AlbumArtist, Album, Genre, Duration, TrackNum, Disc, Year, Size, Ignore, Tempo, Zcr, MeanSpectralCentroid, StdDevSpectralCentroid, MeanSpectralRolloff, StdDevSpectralRolloff, MeanSpectralFlatness, StdDevSpectralFlatness, MeanLoudness, StdDevLoudness, Chroma1, Chroma2, Chroma3, Chroma4, Chroma5, Chroma6, Chroma7, Chroma8, Chroma9, Chroma10, AnalysisVersion, Decoder, Analysed) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+                            params![db_path, meta.title, meta.artist, meta.album_artist, meta.album, meta.genre, meta.duration, meta.track_num, meta.disc, meta.year, meta.size, 0,
                             analysis[AnalysisIndex::Tempo], analysis[AnalysisIndex::Zcr], analysis[AnalysisIndex::MeanSpectralCentroid], analysis[AnalysisIndex::StdDeviationSpectralCentroid], analysis[AnalysisIndex::MeanSpectralRolloff],
                             analysis[AnalysisIndex::StdDeviationSpectralRolloff], analysis[AnalysisIndex::MeanSpectralFlatness], analysis[AnalysisIndex::StdDeviationSpectralFlatness], analysis[AnalysisIndex::MeanLoudness], analysis[AnalysisIndex::StdDeviationLoudness],
                             analysis[AnalysisIndex::Chroma1], analysis[AnalysisIndex::Chroma2], analysis[AnalysisIndex::Chroma3], analysis[AnalysisIndex::Chroma4], analysis[AnalysisIndex::Chroma5],
-                            analysis[AnalysisIndex::Chroma6], analysis[AnalysisIndex::Chroma7], analysis[AnalysisIndex::Chroma8], analysis[AnalysisIndex::Chroma9], analysis[AnalysisIndex::Chroma10]]) {
+                            analysis[AnalysisIndex::Chroma6], analysis[AnalysisIndex::Chroma7], analysis[AnalysisIndex::Chroma8], analysis[AnalysisIndex::Chroma9], analysis[AnalysisIndex::Chroma10], bliss_audio::FEATURES_VERSION, DECODER, analysed]) {
                         Ok(_) => { }
                         Err(e) => { log::error!("Failed to insert '{}' into database. {}", path, e); }
                     }
                 } else {
-                    match self.conn.execute("UPDATE Tracks SET Title=?, Artist=?, AlbumArtist=?, Album=?, Genre=?, Duration=?, Tempo=?, Zcr=?, MeanSpectralCentroid=?, StdDevSpectralCentroid=?, MeanSpectralRolloff=?, StdDevSpectralRolloff=?, MeanSpectralFlatness=?, StdDevSpectralFlatness=?, MeanLoudness=?, StdDevLoudness=?, Chroma1=?, Chroma2=?, Chroma3=?, Chroma4=?, Chroma5=?, Chroma6=?, Chroma7=?, Chroma8=?, Chroma9=?, Chroma10=? WHERE rowid=?;",
-                            params![meta.title, meta.artist, meta.album_artist, meta.album, meta.genre, meta.duration,
+                    // Tracks stored without analysis (e.g. due to duration) were marked as ignored
+                    match self.conn.execute("UPDATE Tracks SET Ignore=CASE WHEN Tempo IS NULL THEN 0 ELSE Ignore END, Title=?, Artist=?, AlbumArtist=?, Album=?, Genre=?, Duration=?, TrackNum=?, Disc=?, Year=?, Size=?, Tempo=?, Zcr=?, MeanSpectralCentroid=?, StdDevSpectralCentroid=?, MeanSpectralRolloff=?, StdDevSpectralRolloff=?, MeanSpectralFlatness=?, StdDevSpectralFlatness=?, MeanLoudness=?, StdDevLoudness=?, Chroma1=?, Chroma2=?, Chroma3=?, Chroma4=?, Chroma5=?, Chroma6=?, Chroma7=?, Chroma8=?, Chroma9=?, Chroma10=?, AnalysisVersion=?, Decoder=?, Analysed=? WHERE rowid=?;",
+                            params![meta.title, meta.artist, meta.album_artist, meta.album, meta.genre, meta.duration, meta.track_num, meta.disc, meta.year, meta.size,
                             analysis[AnalysisIndex::Tempo], analysis[AnalysisIndex::Zcr], analysis[AnalysisIndex::MeanSpectralCentroid], analysis[AnalysisIndex::StdDeviationSpectralCentroid], analysis[AnalysisIndex::MeanSpectralRolloff],
                             analysis[AnalysisIndex::StdDeviationSpectralRolloff], analysis[AnalysisIndex::MeanSpectralFlatness], analysis[AnalysisIndex::StdDeviationSpectralFlatness], analysis[AnalysisIndex::MeanLoudness], analysis[AnalysisIndex::StdDeviationLoudness],
                             analysis[AnalysisIndex::Chroma1], analysis[AnalysisIndex::Chroma2], analysis[AnalysisIndex::Chroma3], analysis[AnalysisIndex::Chroma4], analysis[AnalysisIndex::Chroma5],
-                            analysis[AnalysisIndex::Chroma6], analysis[AnalysisIndex::Chroma7], analysis[AnalysisIndex::Chroma8], analysis[AnalysisIndex::Chroma9], analysis[AnalysisIndex::Chroma10], id]) {
+                            analysis[AnalysisIndex::Chroma6], analysis[AnalysisIndex::Chroma7], analysis[AnalysisIndex::Chroma8], analysis[AnalysisIndex::Chroma9], analysis[AnalysisIndex::Chroma10], bliss_audio::FEATURES_VERSION, DECODER, analysed, id]) {
                         Ok(_) => { }
                         Err(e) => { log::error!("Failed to update '{}' in database. {}", path, e); }
                     }
@@ -166,11 +649,100 @@ impl Db {
         }
     }
 
-    pub fn remove_old(&self, mpaths: &Vec<PathBuf>, dry_run: bool) {
-        log::info!("Looking for non-existent tracks");
-        let mut stmt = self.conn.prepare("SELECT File FROM Tracks;").unwrap();
+    /// Paths of tracks analysed with an older (or unknown) features version.
+    pub fn get_stale_paths(&self) -> HashSet<String> {
+        let mut paths: HashSet<String> = HashSet::new();
+        if let Ok(mut stmt) = self.conn.prepare("SELECT File FROM Tracks WHERE Tempo IS NOT NULL AND (AnalysisVersion IS NULL OR AnalysisVersion < ?);") {
+            if let Ok(rows) = stmt.query_map(params![bliss_audio::FEATURES_VERSION], |row| row.get(0)) {
+                paths.extend(rows.flatten());
+            }
+        }
+        paths
+    }
+
+    /// Times (seconds since epoch) of oldest and newest analysis, None if no tracks
+    /// have a known analysis time.
+    pub fn get_analysed_range(&self) -> Option<(i64, i64)> {
+        let range: (Option<i64>, Option<i64>) = self.conn.query_row("SELECT MIN(Analysed), MAX(Analysed) FROM Tracks WHERE Tempo IS NOT NULL;", [], |row| Ok((row.get(0)?, row.get(1)?))).ok()?;
+        Some((range.0?, range.1?))
+    }
+
+    /// Number of tracks per features version, None is used for unknown versions.
+    pub fn get_analysis_versions(&self) -> Vec<(Option<u16>, usize)> {
+        let mut versions: Vec<(Option<u16>, usize)> = Vec::new();
+        if let Ok(mut stmt) = self.conn.prepare("SELECT AnalysisVersion, COUNT(*) FROM Tracks WHERE Tempo IS NOT NULL GROUP BY AnalysisVersion ORDER BY AnalysisVersion;") {
+            if let Ok(rows) = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))) {
+                versions.extend(rows.flatten());
+            }
+        }
+        versions
+    }
+
+    /// Add track that has not been analysed (e.g. due to its duration). This is marked
+    /// as ignored, so that it is not used in mixes - and has no analysis values, so must
+    /// remain ignored until it is analysed (see UNIGNORE_GUARD).
+    pub fn add_unanalysed(&self, path: &String, meta: &Metadata) {
+        let db_path = normalise(path);
+        match self.conn.execute("INSERT INTO Tracks (File, Title, Artist, AlbumArtist, Album, Genre, Duration, TrackNum, Disc, Year, Size, Ignore) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 1) ON CONFLICT(File) DO UPDATE SET Duration=excluded.Duration, Size=excluded.Size, Ignore=1;",
+                params![db_path, meta.title, meta.artist, meta.album_artist, meta.album, meta.genre, meta.duration, meta.track_num, meta.disc, meta.year, meta.size]) {
+            Ok(_) => { }
+            Err(e) => { log::error!("Failed to add '{}' to database. {}", path, e); }
+        }
+    }
+
+    pub fn get_cue_hash(&self, path: &str) -> Option<String> {
+        let db_path = normalise(path);
+        let mut stmt = self.conn.prepare("SELECT Hash FROM CueFiles WHERE File=:path;").ok()?;
+        let mut hash_iter = stmt.query_map(&[(":path", &db_path)], |row| Ok(row.get(0)?)).ok()?;
+        match hash_iter.next() {
+            Some(Ok(hash)) => Some(hash),
+            _ => None,
+        }
+    }
+
+    pub fn set_cue_hash(&self, path: &str, hash: &str) {
+        let db_path = normalise(path);
+        let cmd = self.conn.execute("INSERT OR REPLACE INTO CueFiles (File, Hash) VALUES (?, ?);", params![db_path, hash]);
+
+        if let Err(e) = cmd {
+            log::error!("Failed to store cue hash of '{}'. {}", path, e);
+        }
+    }
+
+    pub fn remove_cue_tracks(&self, path: &str) {
+        let db_path = normalise(path);
+        let prefix = format!("{}{}", db_path, CUE_MARKER);
+        let cmd = self.conn.execute("DELETE FROM Tracks WHERE instr(File, ?)=1;", params![prefix]);
+
+        if let Err(e) = cmd {
+            log::error!("Failed to remove cue tracks of '{}'. {}", path, e);
+        }
+    }
+
+    /// Remove track, the tracks of a CUE file, or all tracks within a folder.
+    /// Returns number of tracks removed.
+    pub fn remove_path(&self, path: &str) -> usize {
+        let db_path = normalise(path);
+        let cue_prefix = format!("{}{}", db_path, CUE_MARKER);
+        let dir_prefix = format!("{}/", db_path);
+        match self.conn.execute("DELETE FROM Tracks WHERE File=? OR instr(File, ?)=1 OR instr(File, ?)=1;", params![db_path, cue_prefix, dir_prefix]) {
+            Ok(count) => count,
+            Err(e) => {
+                log::error!("Failed to remove '{}' - {}", path, e);
+                0
+            }
+        }
+    }
+
+    /// Get tracks whose file does not exist in any of the music folders. All tracks of
+    /// a CUE file share the same audio file, so these are either all returned, or none.
+    /// Files are checked using 'threads' threads (0 for one per CPU core), as this can
+    /// be slow for network mounted folders.
+    pub fn get_orphans(&self, mpaths: &Vec<PathBuf>, threads: usize) -> Vec<String> {
+        let mut stmt = self.conn.prepare("SELECT File FROM Tracks ORDER BY File ASC;").unwrap();
         let track_iter = stmt.query_map([], |row| Ok((row.get(0)?,))).unwrap();
-        let mut to_remove: Vec<String> = Vec::new();
+        let mut tracks: Vec<(String, String)> = Vec::new();
+        let mut to_check: HashSet<String> = HashSet::new();
         for tr in track_iter {
             let mut db_path: String = tr.unwrap().0;
             let orig_path = db_path.clone();
@@ -183,47 +755,273 @@ impl Db {
             if cfg!(windows) {
                 db_path = db_path.replace("/", "\\");
             }
-            let mut exists = false;
+            // Tracks of a CUE file share the audio file, so this is only checked once
+            to_check.insert(db_path.clone());
+            tracks.push((orig_path, db_path));
+        }
+
+        let to_check: Vec<String> = to_check.into_iter().collect();
+        let threads = if threads == 0 { num_cpus::get() } else { threads };
+        let chunk_size = ((to_check.len() + threads - 1) / threads).max(1);
+        let missing: HashSet<&String> = thread::scope(|scope| {
+            let handles: Vec<_> = to_check
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk.iter()
+                            .filter(|db_path| !mpaths.iter().any(|mpath| fs_path(mpath, db_path).exists()))
+                            .collect::<Vec<&String>>()
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap_or_default()).collect()
+        });
+
+        tracks.into_iter().filter(|(_, db_path)| missing.contains(db_path)).map(|(orig_path, _)| orig_path).collect()
+    }
 
+    // Remove stored hashes of CUE files whose audio file no longer exists
+    fn remove_old_cue_hashes(&self, mpaths: &Vec<PathBuf>) {
+        let mut stmt = self.conn.prepare("SELECT File FROM CueFiles;").unwrap();
+        let file_iter = stmt.query_map([], |row| Ok(row.get::<_, String>(0)?)).unwrap();
+        let mut to_remove: Vec<String> = Vec::new();
+        for file in file_iter.flatten() {
+            let path = if cfg!(windows) { file.replace("/", "\\") } else { file.clone() };
+            if !mpaths.iter().any(|mpath| fs_path(mpath, &path).exists()) {
+                to_remove.push(file);
+            }
+        }
+        for file in to_remove {
+            if let Err(e) = self.conn.execute("DELETE FROM CueFiles WHERE File = ?;", params![file]) {
+                log::error!("Failed to remove cue hash of '{}'. {}", file, e);
+            }
+        }
+    }
+
+    fn get_size(&self, path: &str) -> Option<i64> {
+        self.conn.query_row("SELECT Size FROM Tracks WHERE File=?;", params![path], |row| row.get(0)).ok().flatten()
+    }
+
+    /// Find new location of tracks whose file no longer exists. A track has moved if
+    /// either; its path starts with the 'from' of a 'moved_from' (from, to) prefix
+    /// mapping, and replacing this with 'to' gives a file that exists, or it is not
+    /// a CUE track and exactly one file in the music folders has the same name and
+    /// size, and is not already in DB. Returns list of (old path, new path).
+    fn get_moved(&self, mpaths: &Vec<PathBuf>, orphans: &Vec<String>, moved_from: &[(String, String)]) -> Vec<(String, String)> {
+        let moved_from: Vec<(String, String)> = moved_from.iter().map(|(from, to)| (normalise(from), normalise(to))).collect();
+        let names: HashSet<String> = orphans.iter()
+            .filter(|orphan| !orphan.contains(CUE_MARKER))
+            .map(|orphan| String::from(orphan.rsplit('/').next().unwrap_or(orphan)))
+            .collect();
+        let mut found: HashMap<String, Vec<(PathBuf, String)>> = HashMap::new();
+        if !names.is_empty() {
+            log::info!("Looking for moved tracks");
             for mpath in mpaths {
-                let path = mpath.join(PathBuf::from(db_path.clone()));
-                //log::debug!("Check if '{}' exists.", path.to_string_lossy());
+                find_named_files(mpath, mpath, &names, &mut found);
+            }
+        }
 
-                if path.exists() {
-                    exists = true;
-                    break;
+        let mut used = self.get_all_paths();
+        let mut moved: Vec<(String, String)> = Vec::new();
+        for orphan in orphans {
+            let audio = audio_path(orphan);
+            let mapped = moved_from.iter()
+                .filter(|(from, _)| audio.starts_with(from.as_str()))
+                .map(|(from, to)| format!("{}{}", to, &audio[from.len()..]))
+                .find(|new_audio| mpaths.iter().any(|mpath| fs_path(mpath, new_audio).exists()));
+            let new_path = match mapped {
+                Some(new_audio) => Some(format!("{}{}", new_audio, &orphan[audio.len()..])),
+                None if audio.len() == orphan.len() => {
+                    let name = orphan.rsplit('/').next().unwrap_or(orphan);
+                    let size = self.get_size(orphan);
+                    let candidates: Vec<&String> = found.get(name).map_or(Vec::new(), |files| {
+                        files.iter()
+                            .filter(|(_, file)| !used.contains(file))
+                            .filter(|(path, _)| size.is_some() && fs::metadata(path).ok().map(|m| m.len() as i64) == size)
+                            .map(|(_, file)| file)
+                            .collect()
+                    });
+                    if candidates.len() == 1 { Some(candidates[0].clone()) } else { None }
+                }
+                None => None,
+            };
+            if let Some(new_path) = new_path {
+                if used.insert(new_path.clone()) {
+                    moved.push((orphan.clone(), new_path));
                 }
             }
+        }
+        moved
+    }
 
-            if !exists {
-                to_remove.push(orig_path);
+    /// Remove tracks whose file no longer exists. Nothing is removed if these are more
+    /// than 'max_percent' of all tracks - as then it is more likely that a music folder
+    /// is not mounted, than that the files have been deleted. Otherwise, tracks whose
+    /// file has been moved (see 'get_moved') have their path updated instead, so that
+    /// they are not re-analysed.
+    pub fn remove_old(&self, mpaths: &Vec<PathBuf>, dry_run: bool, threads: usize, moved_from: &[(String, String)], max_percent: usize) {
+        log::info!("Looking for non-existent tracks");
+        let mut to_remove = self.get_orphans(mpaths, threads);
+        // Check before looking for moved files, as that requires reading every music folder
+        let num_missing = to_remove.len();
+        let total = self.get_track_count();
+        if num_missing > 0 && num_missing * 100 > total * max_percent {
+            log::error!("**** Not removing non-existent tracks, as these are {} of {} tracks (more than {}%) ****", num_missing, total, max_percent);
+            log::error!("Please check that all music folders are mounted. Use --force-remove to remove these tracks. Non-existent tracks include:");
+            for t in to_remove.iter().take(MAX_MISSING_LISTED) {
+                log::error!("  {}", t);
+            }
+            if num_missing > MAX_MISSING_LISTED {
+                log::error!("  + {} other(s)", num_missing - MAX_MISSING_LISTED);
             }
+            return;
+        }
+        let moved = if to_remove.is_empty() { Vec::new() } else { self.get_moved(mpaths, &to_remove, moved_from) };
+        if !moved.is_empty() {
+            let moved_paths: HashSet<&String> = moved.iter().map(|(old, _)| old).collect();
+            to_remove.retain(|t| !moved_paths.contains(t));
         }
-
         let num_to_remove = to_remove.len();
-        log::info!("Num non-existent tracks: {}", num_to_remove);
-        if num_to_remove > 0 {
+        log::info!("Num moved tracks: {}, num non-existent tracks: {}", moved.len(), num_to_remove);
+        if num_to_remove > 0 || !moved.is_empty() {
             if dry_run {
-                log::info!("The following need to be removed from database:");
+                for (old, new) in &moved {
+                    report::moved(old, new);
+                }
                 for t in to_remove {
-                    log::info!("  {}", t);
+                    report::removed(&t, "file does not exist");
                 }
+            } else if !self.backup("removing tracks") {
+                log::error!("Not removing tracks, use --no-backup to remove without a backup");
             } else {
                 let count_before = self.get_track_count();
-                for t in to_remove {
-                    //log::debug!("Remove '{}'", t);
-                    let cmd = self.conn.execute("DELETE FROM Tracks WHERE File = ?;", params![t]);
-
-                    if let Err(e) = cmd {
-                        log::error!("Failed to remove '{}' - {}", t, e)
+                let mut num_rescued: usize = 0;
+                // Use a single transaction, as a transaction per track is very slow
+                match self.conn.unchecked_transaction() {
+                    Ok(tx) => {
+                        {
+                            let mut stmt = tx.prepare("UPDATE Tracks SET File = ? WHERE File = ?;").unwrap();
+                            let mut cue_stmt = tx.prepare("UPDATE CueFiles SET File = ? WHERE File = ?;").unwrap();
+                            for (old, new) in &moved {
+                                match stmt.execute(params![new, old]) {
+                                    Ok(_) => {
+                                        num_rescued += 1;
+                                        if old.contains(CUE_MARKER) {
+                                            let _ = cue_stmt.execute(params![audio_path(new), audio_path(old)]);
+                                        }
+                                    }
+                                    Err(e) => { log::error!("Failed to move '{}' to '{}' - {}", old, new, e); }
+                                }
+                            }
+                        }
+                        {
+                            let mut stmt = tx.prepare("DELETE FROM Tracks WHERE File = ?;").unwrap();
+                            for t in to_remove {
+                                //log::debug!("Remove '{}'", t);
+                                if let Err(e) = stmt.execute(params![t]) {
+                                    log::error!("Failed to remove '{}' - {}", t, e)
+                                }
+                            }
+                        }
+                        if let Err(e) = tx.commit() {
+                            log::error!("Failed to remove tracks - {}", e);
+                        }
                     }
+                    Err(e) => { log::error!("Failed to remove tracks - {}", e); }
                 }
                 let count_now = self.get_track_count();
                 if (count_now + num_to_remove) != count_before {
                     log::error!("Failed to remove all tracks. Count before: {}, wanted to remove: {}, count now: {}", count_before, num_to_remove, count_now);
                 }
+                log::info!("Rescued {} moved track(s), removed {} track(s)", num_rescued, count_before.saturating_sub(count_now));
+            }
+        }
+        if !dry_run {
+            self.remove_old_cue_hashes(mpaths);
+        }
+    }
+
+    /// Find tracks whose analysis is all zero, contains NaN/Inf (stored as NULL), or
+    /// is outside of the valid range. Returns list of file and reason.
+    pub fn get_invalid_tracks(&self) -> Vec<(String, String)> {
+        let mut invalid: Vec<(String, String)> = Vec::new();
+        let mut stmt = self.conn.prepare(&format!("SELECT File, {} FROM Tracks ORDER BY File ASC;", ANALYSIS_COLUMNS)).unwrap();
+        let track_iter = stmt
+            .query_map([], |row| {
+                let mut vals: Vec<Option<f64>> = Vec::new();
+                for i in 0..NUM_ANALYSIS_COLUMNS {
+                    vals.push(row.get(i + 1)?);
+                }
+                Ok((row.get::<_, String>(0)?, vals))
+            })
+            .unwrap();
+
+        for tr in track_iter {
+            let (file, vals) = tr.unwrap();
+            if vals.iter().all(|v| v.is_none()) {
+                // Not analysed, e.g. skipped due to duration
+                continue;
+            }
+            if vals.iter().any(|v| v.is_none() || !v.unwrap().is_finite()) {
+                invalid.push((file, String::from("Contains invalid (NaN or infinite) values")));
+            } else if vals.iter().all(|v| v.unwrap() == 0.0) {
+                invalid.push((file, String::from("All values are zero")));
+            } else if vals.iter().any(|v| v.unwrap().abs() > MAX_ANALYSIS_VALUE) {
+                invalid.push((file, String::from("Values are out of range")));
             }
         }
+        invalid
+    }
+
+    /// Get analysis of all tracks, tracks with invalid values are skipped.
+    pub fn get_analysis(&self) -> Vec<TrackAnalysis> {
+        let mut tracks: Vec<TrackAnalysis> = Vec::new();
+        let mut stmt = self.conn.prepare(&format!("SELECT File, Ignore, {} FROM Tracks ORDER BY File ASC;", ANALYSIS_COLUMNS)).unwrap();
+        let track_iter = stmt
+            .query_map([], |row| {
+                let mut vals: Vec<Option<f64>> = Vec::new();
+                for i in 0..NUM_ANALYSIS_COLUMNS {
+                    vals.push(row.get(i + 2)?);
+                }
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?, vals))
+            })
+            .unwrap();
+
+        for tr in track_iter {
+            let (file, ignore, vals) = tr.unwrap();
+            if vals.iter().all(|v| v.is_some() && v.unwrap().is_finite()) {
+                tracks.push(TrackAnalysis {
+                    file: file,
+                    ignore: ignore.unwrap_or(0) == 1,
+                    analysis: vals.iter().map(|v| v.unwrap()).collect(),
+                });
+            }
+        }
+        tracks
+    }
+
+    /// Get album artist (or artist, if not set), album, and analysis of tracks that have
+    /// an album and are not ignored. Tracks with invalid values are skipped.
+    pub fn get_album_analysis(&self) -> Vec<(String, String, Vec<f64>)> {
+        let mut tracks: Vec<(String, String, Vec<f64>)> = Vec::new();
+        let mut stmt = self.conn.prepare(&format!("SELECT COALESCE(NULLIF(AlbumArtist, ''), Artist, ''), Album, {} FROM Tracks WHERE Album IS NOT NULL AND Album != '' AND (Ignore IS NULL OR Ignore=0);", ANALYSIS_COLUMNS)).unwrap();
+        let track_iter = stmt
+            .query_map([], |row| {
+                let mut vals: Vec<Option<f64>> = Vec::new();
+                for i in 0..NUM_ANALYSIS_COLUMNS {
+                    vals.push(row.get(i + 2)?);
+                }
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, vals))
+            })
+            .unwrap();
+
+        for tr in track_iter {
+            let (artist, album, vals) = tr.unwrap();
+            if vals.iter().all(|v| v.is_some() && v.unwrap().is_finite()) {
+                tracks.push((artist, album, vals.iter().map(|v| v.unwrap()).collect()));
+            }
+        }
+        tracks
     }
 
     pub fn get_track_count(&self) -> usize {
@@ -248,7 +1046,7 @@ impl Db {
                     .progress_chars("=> "),
             );
 
-            let mut stmt = self.conn.prepare("SELECT rowid, File, Title, Artist, AlbumArtist, Album, Genre, Duration FROM Tracks ORDER BY File ASC;").unwrap();
+            let mut stmt = self.conn.prepare("SELECT rowid, File, Title, Artist, AlbumArtist, Album, Genre, Duration, TrackNum, Disc, Year, Size FROM Tracks ORDER BY File ASC;").unwrap();
             let track_iter = stmt
                 .query_map([], |row| {
                     Ok(FileMetadata {
@@ -260,6 +1058,10 @@ impl Db {
                         album: row.get(5)?,
                         genre: row.get(6)?,
                         duration: row.get(7)?,
+                        track_num: row.get(8)?,
+                        disc: row.get(9)?,
+                        year: row.get(10)?,
+                        size: row.get(11)?,
                     })
                 })
                 .unwrap();
@@ -275,19 +1077,23 @@ impl Db {
                         album: dbtags.album.unwrap_or_default(),
                         genre: dbtags.genre.unwrap_or_default(),
                         duration: dbtags.duration,
+                        track_num: dbtags.track_num,
+                        disc: dbtags.disc,
+                        year: dbtags.year,
+                        size: dbtags.size,
                     };
                     progress.set_message(format!("{}", dbtags.file));
 
                     for mpath in mpaths {
-                        let track_path = mpath.join(&dbtags.file);
+                        let track_path = fs_path(mpath, &dbtags.file);
                         if track_path.exists() {
                             let path = String::from(track_path.to_string_lossy());
                             let ftags = tags::read(&path);
                             if ftags.is_empty() {
                                 log::error!("Failed to read tags of '{}'", dbtags.file);
                             } else if ftags != dtags {
-                                match self.conn.execute("UPDATE Tracks SET Title=?, Artist=?, AlbumArtist=?, Album=?, Genre=?, Duration=? WHERE rowid=?;",
-                                                        params![ftags.title, ftags.artist, ftags.album_artist, ftags.album, ftags.genre, ftags.duration, dbtags.rowid]) {
+                                match self.conn.execute("UPDATE Tracks SET Title=?, Artist=?, AlbumArtist=?, Album=?, Genre=?, Duration=?, TrackNum=?, Disc=?, Year=?, Size=? WHERE rowid=?;",
+                                                        params![ftags.title, ftags.artist, ftags.album_artist, ftags.album, ftags.genre, ftags.duration, ftags.track_num, ftags.disc, ftags.year, ftags.size, dbtags.rowid]) {
                                     Ok(_) => { updated += 1; }
                                     Err(e) => { log::error!("Failed to update tags of '{}'. {}", dbtags.file, e); }
                                 }
@@ -302,8 +1108,95 @@ impl Db {
         }
     }
 
+    /// Get metadata of all tracks, except those of CUE files.
+    pub fn get_metadata(&self) -> Vec<FileMetadata> {
+        let mut tracks: Vec<FileMetadata> = Vec::new();
+        let mut stmt = self.conn.prepare("SELECT rowid, File, Title, Artist, AlbumArtist, Album, Genre, Duration, TrackNum, Disc, Year, Size FROM Tracks ORDER BY File ASC;").unwrap();
+        let track_iter = stmt
+            .query_map([], |row| {
+                Ok(FileMetadata {
+                    rowid: row.get(0)?,
+                    file: row.get(1)?,
+                    title: row.get(2)?,
+                    artist: row.get(3)?,
+                    album_artist: row.get(4)?,
+                    album: row.get(5)?,
+                    genre: row.get(6)?,
+                    duration: row.get(7)?,
+                    track_num: row.get(8)?,
+                    disc: row.get(9)?,
+                    year: row.get(10)?,
+                    size: row.get(11)?,
+                })
+            })
+            .unwrap();
+        for tr in track_iter.flatten() {
+            if !tr.file.contains(CUE_MARKER) {
+                tracks.push(tr);
+            }
+        }
+        tracks
+    }
+
+    /// Get paths of tracks of the file, or within the folder, 'prefix'. This includes the
+    /// tracks of CUE files.
+    pub fn get_paths_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        let m = path_match(prefix);
+        if let Ok(mut stmt) = self.conn.prepare(&format!("SELECT File FROM Tracks WHERE {} ORDER BY File;", PATH_MATCH)) {
+            if let Ok(rows) = stmt.query_map(params![m[0], m[1], m[2]], |row| row.get(0)) {
+                paths.extend(rows.flatten());
+            }
+        }
+        paths
+    }
+
+    /// Remove tracks, and failures, of the file, or within the folder, 'prefix'. Returns
+    /// number of tracks removed.
+    pub fn remove_prefix(&self, prefix: &str) -> Result<usize, Error> {
+        let m = path_match(prefix);
+        let _ = self.conn.execute(&format!("DELETE FROM Failures WHERE {};", PATH_MATCH), params![m[0], m[1], m[2]]);
+        match self.conn.execute(&format!("DELETE FROM Tracks WHERE {};", PATH_MATCH), params![m[0], m[1], m[2]]) {
+            Ok(count) => Ok(count),
+            Err(e) => Err(Error::Db(format!("Failed to remove '{}'. {}", prefix, e))),
+        }
+    }
+
+    /// Clear Ignore flag of 'files'. As with clear_ignore(), tracks that have not been
+    /// analysed remain ignored. Returns number of tracks updated.
+    pub fn unset_ignore_files(&self, files: &[String]) -> usize {
+        let mut count: usize = 0;
+        let tx = match self.conn.unchecked_transaction() {
+            Ok(tx) => tx,
+            Err(e) => {
+                log::error!("Failed to un-ignore tracks. {}", e);
+                return 0;
+            }
+        };
+        {
+            let mut stmt = match tx.prepare(&format!("UPDATE Tracks SET Ignore=0 WHERE File=? AND Ignore=1 AND {};", UNIGNORE_GUARD)) {
+                Ok(stmt) => stmt,
+                Err(e) => {
+                    log::error!("Failed to un-ignore tracks. {}", e);
+                    return 0;
+                }
+            };
+            for file in files {
+                match stmt.execute(params![file]) {
+                    Ok(updated) => { count += updated; }
+                    Err(e) => { log::error!("Failed to un-ignore '{}'. {}", file, e); }
+                }
+            }
+        }
+        if let Err(e) = tx.commit() {
+            log::error!("Failed to un-ignore tracks. {}", e);
+            return 0;
+        }
+        count
+    }
+
     pub fn clear_ignore(&self) {
-        let cmd = self.conn.execute("UPDATE Tracks SET Ignore=0;", []);
+        let cmd = self.conn.execute(&format!("UPDATE Tracks SET Ignore=0 WHERE {};", UNIGNORE_GUARD), []);
 
         if let Err(e) = cmd {
             log::error!("Failed clear Ignore column. {}", e);
@@ -312,19 +1205,204 @@ impl Db {
 
     pub fn set_ignore(&self, line: &str) {
         log::info!("Ignore: {}", line);
-        if line.starts_with("SQL:") {
+        self.update_ignore(line, 1);
+    }
+
+    pub fn unset_ignore(&self, line: &str) {
+        log::info!("Un-ignore: {}", line);
+        self.update_ignore(line, 0);
+    }
+
+    fn update_ignore(&self, line: &str, val: u8) {
+        let guard = if val == 0 { format!(" AND {}", UNIGNORE_GUARD) } else { String::new() };
+        if let Some(pattern) = line.strip_prefix("glob:") {
+            match GlobBuilder::new(pattern).literal_separator(true).build() {
+                Ok(glob) => {
+                    let matcher = glob.compile_matcher();
+                    self.update_ignore_matching(line, val, |file| matcher.is_match(file));
+                }
+                Err(e) => { log::error!("Invalid glob pattern '{}'. {}", line, e); }
+            }
+        } else if let Some(pattern) = line.strip_prefix("regex:") {
+            match Regex::new(pattern) {
+                Ok(re) => { self.update_ignore_matching(line, val, |file| re.is_match(file)); }
+                Err(e) => { log::error!("Invalid regular expression '{}'. {}", line, e); }
+            }
+        } else if line.starts_with("SQL:") {
             let sql = &line[4..];
-            let cmd = self.conn.execute(&format!("UPDATE Tracks Set Ignore=1 WHERE {}", sql), []);
+            let cmd = self.conn.execute(&format!("UPDATE Tracks Set Ignore={} WHERE ({}){}", val, sql, guard), []);
 
             if let Err(e) = cmd {
                 log::error!("Failed set Ignore column for '{}'. {}", line, e);
             }
         } else {
-            let cmd = self.conn.execute(&format!("UPDATE Tracks SET Ignore=1 WHERE File LIKE \"{}%\"", line), []);
+            // Matched in the same way as the remove task
+            let m = path_match(line);
+            let cmd = self.conn.execute(&format!("UPDATE Tracks SET Ignore=? WHERE {}{}", PATH_MATCH, guard), params![val, m[0], m[1], m[2]]);
 
             if let Err(e) = cmd {
                 log::error!("Failed set Ignore column for '{}'. {}", line, e);
             }
         }
     }
+
+    // SQLite has no equivalent of regex patterns. Its GLOB does not distinguish '*' from
+    // '**', and has no '{a,b}' alternatives - so glob patterns are also checked here.
+    fn update_ignore_matching<F: Fn(&str) -> bool>(&self, line: &str, val: u8, matches: F) {
+        let files: Vec<String> = match self.conn.prepare("SELECT File FROM Tracks;") {
+            Ok(mut stmt) => match stmt.query_map([], |row| row.get(0)) {
+                Ok(rows) => rows.flatten().filter(|f: &String| matches(f)).collect(),
+                Err(_) => Vec::new(),
+            },
+            Err(_) => Vec::new(),
+        };
+        log::debug!("'{}' matched {} track(s)", line, files.len());
+        if files.is_empty() {
+            return;
+        }
+        let guard = if val == 0 { format!(" AND {}", UNIGNORE_GUARD) } else { String::new() };
+        match self.conn.unchecked_transaction() {
+            Ok(tx) => {
+                match tx.prepare(&format!("UPDATE Tracks SET Ignore=? WHERE File=?{};", guard)) {
+                    Ok(mut stmt) => {
+                        for file in files {
+                            if let Err(e) = stmt.execute(params![val, file]) {
+                                log::error!("Failed set Ignore column for '{}'. {}", file, e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Failed set Ignore column for '{}'. {}", line, e);
+                        return;
+                    }
+                }
+                if let Err(e) = tx.commit() {
+                    log::error!("Failed set Ignore column for '{}'. {}", line, e);
+                }
+            }
+            Err(e) => { log::error!("Failed set Ignore column for '{}'. {}", line, e); }
+        }
+    }
+
+    pub fn get_ignored(&self) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        if let Ok(mut stmt) = self.conn.prepare("SELECT File FROM Tracks WHERE Ignore=1 ORDER BY File;") {
+            if let Ok(rows) = stmt.query_map([], |row| row.get(0)) {
+                paths.extend(rows.flatten());
+            }
+        }
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_db() -> Db {
+        let db = Db::new(&String::from(":memory:")).unwrap();
+        db.init().unwrap();
+        // Nothing to backup
+        db.backed_up.set(true);
+        db
+    }
+
+    fn add(db: &Db, file: &str) {
+        db.conn.execute("INSERT INTO Tracks (File, Ignore, Tempo) VALUES (?, 0, 0.5);", params![file]).unwrap();
+    }
+
+    #[test]
+    fn long_paths() {
+        assert_eq!(windows_long_path(r"C:\Music"), r"\\?\C:\Music");
+        assert_eq!(windows_long_path(r"\\nas\music"), r"\\?\UNC\nas\music");
+    }
+
+    #[test]
+    fn unc_root() {
+        let root = r"\\nas\music\Artist\Album";
+        assert_eq!(db_separators(root), "//nas/music/Artist/Album");
+        assert_eq!(windows_separators(&db_separators(root)), root);
+        let file = format!("{}\\track.flac", windows_long_path(root));
+        assert_eq!(file, r"\\?\UNC\nas\music\Artist\Album\track.flac");
+    }
+
+    #[test]
+    fn long_relative_path() {
+        let db_path = format!("{}track.flac", "Composer - Symphony No. 9 in D minor/".repeat(8));
+        assert!(db_path.len() > 260);
+        let windows = windows_separators(&db_path);
+        assert!(!windows.contains('/'));
+        assert_eq!(db_separators(&windows), db_path);
+        let full = format!("{}\\{}", windows_long_path(r"C:\Music"), windows);
+        assert!(full.starts_with(r"\\?\C:\Music\Composer - Symphony No. 9 in D minor\"));
+        assert_eq!(db_separators(&full[r"\\?\C:\Music\".len()..]), db_path);
+    }
+
+    #[test]
+    fn ignore_prefix() {
+        let db = memory_db();
+        for file in ["Rock/a.mp3", "Rockabilly/b.mp3", "rock/c.mp3", "100% Hits/d.mp3", "100 Hits/e.mp3", "12\" Mixes/f.mp3"] {
+            add(&db, file);
+        }
+        db.set_ignore("Rock");
+        db.set_ignore("100% Hits/");
+        db.set_ignore("12\" Mixes");
+        assert_eq!(db.get_ignored(), vec!["100% Hits/d.mp3", "12\" Mixes/f.mp3", "Rock/a.mp3"]);
+        db.unset_ignore("Rock/a.mp3");
+        assert_eq!(db.get_ignored(), vec!["100% Hits/d.mp3", "12\" Mixes/f.mp3"]);
+    }
+
+    #[test]
+    fn unignore_skips_unanalysed() {
+        let db = memory_db();
+        add(&db, "Album/analysed.mp3");
+        db.conn.execute("INSERT INTO Tracks (File, Ignore) VALUES ('Album/unanalysed.mp3', 1);", []).unwrap();
+        db.set_ignore("Album");
+        db.unset_ignore("Album");
+        assert_eq!(db.get_ignored(), vec!["Album/unanalysed.mp3"]);
+        db.set_ignore("Album");
+        db.unset_ignore("SQL:File LIKE 'Album/%' OR 1=1");
+        assert_eq!(db.get_ignored(), vec!["Album/unanalysed.mp3"]);
+        db.set_ignore("glob:**/*.mp3");
+        db.unset_ignore("glob:**/*.mp3");
+        assert_eq!(db.get_ignored(), vec!["Album/unanalysed.mp3"]);
+        db.set_ignore("regex:\\.mp3$");
+        db.unset_ignore("regex:\\.mp3$");
+        assert_eq!(db.get_ignored(), vec!["Album/unanalysed.mp3"]);
+    }
+
+    #[test]
+    fn unanalysed_stays_ignored() {
+        let db = memory_db();
+        let file = String::from("Ambient/six hours.flac");
+        db.add_unanalysed(&file, &Metadata { duration: 21600, ..Metadata::default() });
+        db.clear_ignore();
+        assert_eq!(db.unset_ignore_files(&[file.clone()]), 0);
+        db.unset_ignore("Ambient");
+        assert_eq!(db.get_ignored(), vec![file.clone()]);
+
+        // Analysing track clears Ignore
+        db.add_track(&file, &Metadata { duration: 21600, ..Metadata::default() }, &Analysis::new([0.5; bliss_audio::NUMBER_FEATURES]));
+        assert!(db.get_ignored().is_empty());
+    }
+
+    #[test]
+    fn remove_old_cue_tracks() {
+        let mpath = std::env::temp_dir().join(format!("bliss-analyser-test-{}-remove-old", std::process::id()));
+        fs::create_dir_all(mpath.join("Album")).unwrap();
+        fs::write(mpath.join("Album/kept.mp3"), b"").unwrap();
+        let db = memory_db();
+        add(&db, "Album/kept.mp3");
+        for track in 1..=3 {
+            add(&db, &format!("Album/album.flac{}{}", CUE_MARKER, track));
+        }
+        db.set_cue_hash("Album/album.flac", "hash");
+
+        // Audio file of all 3 CUE tracks does not exist
+        db.remove_old(&vec![mpath.clone()], false, 1, &[], 100);
+        let _ = fs::remove_dir_all(&mpath);
+
+        assert_eq!(db.get_all_paths(), HashSet::from([String::from("Album/kept.mp3")]));
+        assert_eq!(db.conn.query_row("SELECT COUNT(*) FROM CueFiles;", [], |row| row.get::<_, i64>(0)).unwrap(), 0);
+    }
 }
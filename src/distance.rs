@@ -0,0 +1,125 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+pub const METRIC_NAMES: &str = "euclidean, cosine, manhattan";
+
+/// Metric used to calculate distance between the analysis of two tracks.
+#[derive(Clone, Copy)]
+pub enum Metric {
+    Euclidean,
+    Cosine,
+    Manhattan,
+}
+
+impl Metric {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "euclidean" => Some(Metric::Euclidean),
+            "cosine" => Some(Metric::Cosine),
+            "manhattan" => Some(Metric::Manhattan),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Metric::Euclidean => "euclidean",
+            Metric::Cosine => "cosine",
+            Metric::Manhattan => "manhattan",
+        }
+    }
+
+    pub fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        match self {
+            Metric::Euclidean => euclidean(a, b),
+            Metric::Cosine => cosine(a, b),
+            Metric::Manhattan => manhattan(a, b),
+        }
+    }
+}
+
+pub fn euclidean(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum::<f64>().sqrt()
+}
+
+/// 1 - cosine similarity, so 0 for vectors pointing the same way, and 2 for
+/// opposite vectors.
+pub fn cosine(a: &[f64], b: &[f64]) -> f64 {
+    let dot: f64 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 1.0;
+    }
+    1.0 - (dot / (norm_a * norm_b))
+}
+
+pub fn manhattan(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y).abs()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 1e-6;
+
+    // Analysis values are read from DB as f64
+    fn values(vals: [f32; 20]) -> Vec<f64> {
+        vals.iter().map(|v| *v as f64).collect()
+    }
+
+    fn unit(index: usize) -> Vec<f64> {
+        let mut vals = [0.0f32; 20];
+        vals[index] = 1.0;
+        values(vals)
+    }
+
+    #[test]
+    fn identical() {
+        let mut vals = [0.0f32; 20];
+        for (i, v) in vals.iter_mut().enumerate() {
+            *v = (i as f32 / 10.0) - 1.0;
+        }
+        let a = values(vals);
+        assert!(euclidean(&a, &a).abs() < EPSILON);
+        assert!(cosine(&a, &a).abs() < EPSILON);
+        assert!(manhattan(&a, &a).abs() < EPSILON);
+    }
+
+    #[test]
+    fn orthogonal() {
+        let (a, b) = (unit(0), unit(19));
+        assert!((euclidean(&a, &b) - 2f64.sqrt()).abs() < EPSILON);
+        assert!((cosine(&a, &b) - 1.0).abs() < EPSILON);
+        assert!((manhattan(&a, &b) - 2.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn opposite() {
+        let (a, b) = (values([0.5; 20]), values([-0.5; 20]));
+        assert!((euclidean(&a, &b) - 20f64.sqrt()).abs() < EPSILON);
+        assert!((cosine(&a, &b) - 2.0).abs() < EPSILON);
+        assert!((manhattan(&a, &b) - 20.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn cosine_ignores_magnitude() {
+        let (a, b) = (values([0.25; 20]), values([0.75; 20]));
+        assert!(cosine(&a, &b).abs() < EPSILON);
+        assert!((cosine(&a, &values([0.0; 20])) - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn metric_names() {
+        for name in METRIC_NAMES.split(", ") {
+            assert_eq!(Metric::from_name(name).map(|m| m.name()), Some(name));
+        }
+        assert!(Metric::from_name("chebyshev").is_none());
+    }
+}
@@ -0,0 +1,160 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::db;
+use crate::error::Error;
+use crate::upload;
+use bliss_audio::decoder::{Decoder, ffmpeg::FFmpeg};
+use std::env;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const TEST_CLIP_RATE: u32 = 22050;
+const TEST_CLIP_SECS: u32 = 5;
+
+struct Checks {
+    failed: usize,
+}
+
+impl Checks {
+    fn report(&mut self, name: &str, res: Result<String, String>) {
+        match res {
+            Ok(msg) => { log::info!("PASS: {} - {}", name, msg); }
+            Err(msg) => {
+                log::error!("FAIL: {} - {}", name, msg);
+                self.failed += 1;
+            }
+        }
+    }
+}
+
+// Create a few seconds of mono 16-bit WAV containing a chord, so that there is
+// something for the analysis to find.
+fn test_clip() -> Vec<u8> {
+    let num_samples = TEST_CLIP_RATE * TEST_CLIP_SECS;
+    let data_len = num_samples * 2;
+    let mut wav: Vec<u8> = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // Mono
+    wav.extend_from_slice(&TEST_CLIP_RATE.to_le_bytes());
+    wav.extend_from_slice(&(TEST_CLIP_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for i in 0..num_samples {
+        let t = i as f64 / TEST_CLIP_RATE as f64;
+        let val = [261.63, 329.63, 392.0].iter().map(|f| (2.0 * PI * f * t).sin()).sum::<f64>() / 4.0;
+        wav.extend_from_slice(&((val * i16::MAX as f64) as i16).to_le_bytes());
+    }
+    wav
+}
+
+fn check_decoder() -> Result<String, String> {
+    let path = env::temp_dir().join(format!("bliss-analyser-doctor-{}.wav", process::id()));
+    if let Err(e) = fs::write(&path, test_clip()) {
+        return Err(format!("Failed to write test clip ({}). {}", path.to_string_lossy(), e));
+    }
+    let res = <FFmpeg as Decoder>::song_from_path(&path);
+    let _ = fs::remove_file(&path);
+    match res {
+        Ok(song) if song.duration.as_millis() > 0 => Ok(format!("ffmpeg decoded and analysed {}s test clip", song.duration.as_secs())),
+        Ok(_) => Err("ffmpeg decoded no audio from test clip".to_string()),
+        Err(e) => Err(format!("Failed to analyse test clip. {}", e)),
+    }
+}
+
+fn check_db_folder(db_path: &str) -> Result<String, String> {
+    let folder = match Path::new(db_path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let test = PathBuf::from(format!("{}.doctor", db_path));
+    match fs::write(&test, b"test") {
+        Ok(_) => {
+            let _ = fs::remove_file(&test);
+            Ok(format!("{} is writable", folder.to_string_lossy()))
+        }
+        Err(e) => Err(format!("{} is not writable. {}", folder.to_string_lossy(), e)),
+    }
+}
+
+fn check_db(db_path: &str) -> Result<String, String> {
+    if !Path::new(db_path).exists() {
+        return Ok(format!("{} does not exist, and will be created", db_path));
+    }
+    let db = db::Db::new(&String::from(db_path)).map_err(|e| e.to_string())?;
+    let version = db.get_version();
+    let features = db.get_info(db::INFO_FEATURES_VERSION);
+    db.close();
+    if version > db::DB_VERSION {
+        return Err(format!("Schema version {} is newer than supported version {}", version, db::DB_VERSION));
+    }
+    if let Some(features) = features {
+        if features != bliss_audio::FEATURES_VERSION.to_string() {
+            return Err(format!("Features version {} does not match {}, tracks should be re-analysed", features, bliss_audio::FEATURES_VERSION));
+        }
+    }
+    Ok(format!("Schema version {}", version))
+}
+
+fn check_music_path(mpath: &PathBuf) -> Result<String, String> {
+    if !mpath.exists() {
+        return Err("Does not exist".to_string());
+    }
+    if !mpath.is_dir() {
+        return Err("Is not a directory".to_string());
+    }
+    match fs::read_dir(mpath) {
+        Ok(entries) => Ok(format!("Readable, {} item(s)", entries.count())),
+        Err(e) => Err(format!("Not readable. {}", e)),
+    }
+}
+
+fn check_lms(lms: &upload::Lms) -> Result<String, String> {
+    match upload::server_version(lms) {
+        Ok(version) => Ok(format!("LMS {} at {}:{}", version, lms.host, lms.json_port)),
+        Err(e) => Err(format!("{}:{} - {}", lms.host, lms.json_port, e)),
+    }
+}
+
+fn check_plugin(lms: &upload::Lms) -> Result<String, String> {
+    match upload::plugin_available(lms) {
+        Ok(true) => Ok("Bliss Mixer plugin is installed".to_string()),
+        Ok(false) => Err("Bliss Mixer plugin is not installed".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Check the decoder, DB, music folders, and LMS, reporting PASS or FAIL for each.
+pub fn run(db_path: &str, mpaths: &Vec<PathBuf>, lms: &upload::Lms) -> Result<(), Error> {
+    let mut checks = Checks { failed: 0 };
+    checks.report("Decoder", check_decoder());
+    checks.report("DB folder", check_db_folder(db_path));
+    checks.report("DB", check_db(db_path));
+    for mpath in mpaths {
+        checks.report(&format!("Music folder {}", mpath.to_string_lossy()), check_music_path(mpath));
+    }
+    let lms_ok = check_lms(lms);
+    let reachable = lms_ok.is_ok();
+    checks.report("LMS", lms_ok);
+    if reachable {
+        checks.report("LMS plugin", check_plugin(lms));
+    }
+    if checks.failed > 0 {
+        return Err(Error::Config(format!("{} check(s) failed", checks.failed)));
+    }
+    log::info!("All checks passed");
+    Ok(())
+}
@@ -0,0 +1,42 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use std::fmt;
+
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_PARTIAL: i32 = 1;
+pub const EXIT_CONFIG: i32 = 2;
+pub const EXIT_DB: i32 = 3;
+pub const EXIT_LMS: i32 = 4;
+pub const EXIT_TERMINATED: i32 = 5;
+
+/// Errors returned by tasks, each of which maps to a distinct exit code.
+#[derive(Debug)]
+pub enum Error {
+    Config(String),
+    Db(String),
+    Lms(String),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => EXIT_CONFIG,
+            Error::Db(_) => EXIT_DB,
+            Error::Lms(_) => EXIT_LMS,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(msg) | Error::Db(msg) | Error::Lms(msg) => write!(f, "{}", msg),
+        }
+    }
+}
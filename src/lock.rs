@@ -0,0 +1,90 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::error::{self, Error};
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+
+static LOCK_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // Signal 0 only checks whether process exists. EPERM means it exists, but is
+    // owned by another user.
+    unsafe {
+        libc::kill(pid as libc::pid_t, 0) == 0
+            || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Create '<db>.lock' containing our process ID, so that only one instance updates
+/// DB at a time. Fails if lock is held by another (running) process. If 'force' is
+/// set any existing lock file is removed first.
+pub fn acquire(db_path: &str, force: bool) -> Result<(), Error> {
+    let path = PathBuf::from(format!("{}.lock", db_path));
+    if force && path.exists() {
+        log::info!("Removing lock file ({})", path.to_string_lossy());
+        let _ = fs::remove_file(&path);
+    }
+
+    for _ in 0..2 {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                let _ = write!(file, "{}", process::id());
+                if let Ok(mut lock_path) = LOCK_PATH.lock() {
+                    *lock_path = Some(path);
+                }
+                return Ok(());
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let pid = fs::read_to_string(&path).ok().and_then(|p| p.trim().parse::<u32>().ok());
+                match pid {
+                    Some(pid) if process_alive(pid) => {
+                        return Err(Error::Db(format!("Database is in use by another instance (PID {}). If this is not the case, use --force-unlock", pid)));
+                    }
+                    _ => {
+                        log::warn!("Removing stale lock file ({})", path.to_string_lossy());
+                        let _ = fs::remove_file(&path);
+                    }
+                }
+            }
+            Err(e) => {
+                return Err(Error::Db(format!("Failed to create lock file ({}). {}", path.to_string_lossy(), e)));
+            }
+        }
+    }
+    Err(Error::Db(format!("Failed to create lock file ({})", path.to_string_lossy())))
+}
+
+/// Remove lock file, if we created one.
+pub fn release() {
+    if let Ok(mut lock_path) = LOCK_PATH.lock() {
+        if let Some(path) = lock_path.take() {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+/// Handle Ctrl-C and SIGTERM by removing lock file and exiting.
+pub fn release_on_signal() {
+    if let Err(e) = ctrlc::set_handler(|| {
+        release();
+        process::exit(error::EXIT_TERMINATED);
+    }) {
+        log::error!("Failed to install signal handler. {}", e);
+        process::exit(-1);
+    }
+}
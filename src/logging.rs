@@ -0,0 +1,160 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use chrono::Local;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+const MAX_LOG_SIZE: u64 = 5 * 1024 * 1024;
+const NUM_OLD_LOGS: usize = 3;
+const TARGET: &str = "bliss_analyser";
+/// Target of end of run summary messages, these are shown even if log level is 'warn'.
+pub const SUMMARY: &str = "bliss_analyser::summary";
+
+static LOG_FILE: Mutex<Option<RotatingFile>> = Mutex::new(None);
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.clone(),
+            file: file,
+            size: size,
+        })
+    }
+
+    fn old_path(&self, index: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.to_string_lossy(), index))
+    }
+
+    // Move log.2 to log.3, log.1 to log.2, log to log.1, and start a new log
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for i in (1..NUM_OLD_LOGS).rev() {
+            let from = self.old_path(i);
+            if from.exists() {
+                fs::rename(&from, self.old_path(i + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.old_path(1))?;
+        self.file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + buf.len() as u64 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Open log file, all log output will then also be written to this.
+pub fn init_file(path: &str) -> io::Result<()> {
+    let file = RotatingFile::open(&PathBuf::from(path))?;
+    if let Ok(mut log_file) = LOG_FILE.lock() {
+        *log_file = Some(file);
+    }
+    Ok(())
+}
+
+fn write_to_file(buf: &[u8]) {
+    if let Ok(mut log_file) = LOG_FILE.lock() {
+        if let Some(file) = log_file.as_mut() {
+            let _ = file.write_all(buf);
+        }
+    }
+}
+
+/// Log one JSON object per line, instead of text.
+pub fn set_json(json: bool) {
+    JSON_FORMAT.store(json, Ordering::Relaxed);
+}
+
+/// Format log record as JSON. 'file' is set for messages relating to a specific file.
+pub fn format_json(level: log::Level, target: &str, msg: &str, file: Option<&str>) -> String {
+    let mut record = json!({
+        "timestamp": Local::now().to_rfc3339(),
+        "level": level.as_str(),
+        "target": target,
+        "message": msg,
+    });
+    if let Some(file) = file {
+        record["file"] = json!(file);
+    }
+    record.to_string()
+}
+
+fn format_text(level: log::Level, msg: &str) -> String {
+    format!("[{} {:.1}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), level, msg)
+}
+
+/// Write message to log file only, used for output that is truncated on screen.
+pub fn file_only(level: log::Level, msg: &str) {
+    let line = if JSON_FORMAT.load(Ordering::Relaxed) { format_json(level, TARGET, msg.trim(), None) } else { format_text(level, msg) };
+    write_to_file(format!("{}\n", line).as_bytes());
+}
+
+/// Log error relating to a specific file. For JSON output the file is a separate field,
+/// so that each file is a searchable record. If 'screen' is not set, this is only
+/// written to log file.
+pub fn file_error(level: log::Level, file: &str, error: &str, screen: bool) {
+    if JSON_FORMAT.load(Ordering::Relaxed) {
+        if !log::log_enabled!(target: TARGET, level) {
+            return;
+        }
+        let line = format!("{}\n", format_json(level, TARGET, if error.is_empty() { file } else { error }, Some(file)));
+        if screen {
+            let _ = io::stderr().write_all(line.as_bytes());
+        }
+        write_to_file(line.as_bytes());
+    } else {
+        let msg = if error.is_empty() { format!("  {}", file) } else { format!("  {} - {}", file, error) };
+        if screen {
+            log::log!(level, "{}", msg);
+        } else {
+            file_only(level, &msg);
+        }
+    }
+}
+
+/// Writer used by logger to send output to both stderr and log file.
+pub struct Tee;
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        write_to_file(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        if let Ok(mut log_file) = LOG_FILE.lock() {
+            if let Some(file) = log_file.as_mut() {
+                file.file.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -6,7 +6,7 @@
  *
  **/
 
-use argparse::{ArgumentParser, Store, StoreTrue};
+use argparse::{ArgumentParser, Collect, Store, StoreOption, StoreTrue};
 use chrono::Local;
 use configparser::ini::Ini;
 use dirs;
@@ -14,171 +14,966 @@ use log::LevelFilter;
 use std::io::Write;
 use std::path::PathBuf;
 use std::process;
+mod album;
 mod analyse;
+mod cluster;
+mod config;
+mod cue;
+mod daemon;
 mod db;
+mod distance;
+mod doctor;
+mod error;
+mod lock;
+mod logging;
+mod notify;
+mod playlist;
+mod report;
+mod scancache;
+mod status;
 mod tags;
 mod upload;
+mod watch;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 const TOP_LEVEL_INI_TAG: &str = "Bliss";
+const DEFAULT_DB: &str = "bliss.db";
+const DEFAULT_LOGGING: &str = "info";
+const DEFAULT_LOG_FORMAT: &str = "text";
+const DEFAULT_IGNORE: &str = "ignore.txt";
+const DEFAULT_LMS_HOST: &str = "127.0.0.1";
+const DEFAULT_LMS_JSON_PORT: u16 = 9000;
+const DEFAULT_LMS_SCHEME: &str = "http";
+const DEFAULT_LMS_PATH: &str = "/jsonrpc.js";
+const DEFAULT_NET_TIMEOUT: u64 = 10;
+const DEFAULT_WATCH_DELAY: u64 = 30;
+const DEFAULT_INTERVAL: &str = "24h";
+const DEFAULT_PLAYLIST_COUNT: usize = 25;
+const DEFAULT_PLAYLIST_FILE: &str = "playlist.m3u";
+const DEFAULT_METRIC: &str = "euclidean";
+const DEFAULT_CLUSTERS: usize = 10;
+const DEFAULT_CLUSTER_FOLDER: &str = "clusters";
+const DEFAULT_ALBUMS_FILE: &str = "albums.csv";
+const DEFAULT_UNICODE_FORM: &str = "nfc";
+const DEFAULT_ORDER: &str = "path";
+const TASKS: &[&str] = &[
+    "analyse", "retry-failed", "tags", "sync-tags", "ignore", "un-ignore", "list-ignored", "upload", "stopmixer", "info", "verify",
+    "playlist", "cluster", "album-vectors", "normalise", "remove", "find-orphans", "doctor", "retry", "dump-config",
+];
+
+fn config_str(config: &Option<Ini>, key: &str) -> Option<String> {
+    match config {
+        Some(ini) => ini.get(TOP_LEVEL_INI_TAG, key),
+        None => None,
+    }
+}
+
+fn config_val<T: std::str::FromStr>(config: &Option<Ini>, key: &str) -> Option<T> {
+    let val = config_str(config, key)?;
+    match val.trim().parse::<T>() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            log::error!("Invalid value for '{}' ({}) in config file", key, val);
+            process::exit(error::EXIT_CONFIG);
+        }
+    }
+}
+
+fn config_bool(config: &Option<Ini>, key: &str) -> bool {
+    match config_str(config, key) {
+        Some(val) => {
+            let val = val.trim().to_ascii_lowercase();
+            if val == "true" || val == "yes" || val == "on" || val == "1" {
+                true
+            } else if val == "false" || val == "no" || val == "off" || val == "0" {
+                false
+            } else {
+                log::error!("Invalid value for '{}' ({}) in config file", key, val);
+                process::exit(error::EXIT_CONFIG);
+            }
+        }
+        None => false,
+    }
+}
+
+// Read key, and then key_1, key_2, etc. in numeric order. There is no limit on the
+// number of items, and gaps in the numbering are allowed.
+fn config_list(config: &Option<Ini>, key: &str) -> Vec<String> {
+    let mut vals: Vec<String> = Vec::new();
+    if let Some(val) = config_str(config, key) {
+        vals.push(val);
+    }
+    let prefix = format!("{}_", key);
+    let mut numbered: Vec<(usize, String)> = match config.as_ref().and_then(|ini| ini.get_map_ref().get(&TOP_LEVEL_INI_TAG.to_lowercase())) {
+        Some(items) => items.iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix(prefix.as_str())?.parse::<usize>().ok()?, v.clone()?)))
+            .collect(),
+        None => Vec::new(),
+    };
+    numbered.sort_by_key(|(num, _)| *num);
+    vals.extend(numbered.into_iter().map(|(_, val)| val));
+    vals
+}
+
+// Print resolved configuration in config file format, so that output can be used
+// as config file. Empty values are commented out, and lists are written as key,
+// key_1, key_2, etc.
+fn dump_config(entries: &Vec<(&str, Vec<String>)>) {
+    println!("[{}]", TOP_LEVEL_INI_TAG);
+    for (key, vals) in entries {
+        if vals.is_empty() || (vals.len() == 1 && vals[0].is_empty()) {
+            println!("# {}=", key);
+        }
+        for (i, val) in vals.iter().enumerate().filter(|(_, v)| !v.is_empty()) {
+            if i == 0 {
+                println!("{}={}", key, val);
+            } else {
+                println!("{}_{}={}", key, i, val);
+            }
+        }
+    }
+}
+
+// Exit with code matching error class, if task failed
+fn check<T>(res: Result<T, error::Error>) -> T {
+    match res {
+        Ok(val) => val,
+        Err(e) => {
+            log::error!("{}", e);
+            lock::release();
+            process::exit(e.exit_code());
+        }
+    }
+}
+
+// Analysis was stopped via Ctrl-C or SIGTERM, so exit with distinct code
+fn exit_if_terminated() {
+    if analyse::terminated() {
+        lock::release();
+        process::exit(error::EXIT_TERMINATED);
+    }
+}
+
+// Remove duplicate music paths, and warn about any that are within another, as
+// the tracks of these would be analysed twice.
+fn check_music_paths(music_paths: &mut Vec<PathBuf>) {
+    let mut canonical: Vec<PathBuf> = Vec::new();
+    let mut unique: Vec<PathBuf> = Vec::new();
+    for path in music_paths.iter() {
+        let cpath = path.canonicalize().unwrap_or(path.clone());
+        if canonical.contains(&cpath) {
+            log::warn!("Ignoring duplicate music path ({})", path.to_string_lossy());
+            continue;
+        }
+        for other in &canonical {
+            if cpath.starts_with(other) || other.starts_with(&cpath) {
+                log::warn!("Music paths {} and {} overlap, tracks within both will be analysed twice",
+                           path.to_string_lossy(), other.to_string_lossy());
+            }
+        }
+        canonical.push(cpath);
+        unique.push(path.clone());
+    }
+    *music_paths = unique;
+}
 
 fn main() {
     let mut config_file = "config.ini".to_string();
-    let mut db_path = "bliss.db".to_string();
-    let mut logging = "info".to_string();
-    let mut music_path = ".".to_string();
-    let mut ignore_file = "ignore.txt".to_string();
+    let mut db_path: Option<String> = None;
+    let mut logging: Option<String> = None;
+    let mut log_file: Option<String> = None;
+    let mut log_format: Option<String> = None;
+    let mut quiet: bool = false;
+    let mut no_color: bool = false;
+    let mut music_path: Vec<String> = Vec::new();
+    let mut exclude: Vec<String> = Vec::new();
+    let mut exclude_file: Option<String> = None;
+    let mut skip_markers: Vec<String> = Vec::new();
+    let mut ignore_file: Option<String> = None;
     let mut keep_old: bool = false;
+    let mut moved_from: Vec<String> = Vec::new();
     let mut dry_run: bool = false;
+    let mut report_file: Option<String> = None;
     let mut task = "".to_string();
-    let mut lms_host = "127.0.0.1".to_string();
-    let mut max_num_files: usize = 0;
+    let mut lms_host: Option<String> = None;
+    let mut lms_json_port: Option<u16> = None;
+    let mut lms_scheme: Option<String> = None;
+    let mut lms_path: Option<String> = None;
+    let mut lms_user: Option<String> = None;
+    let mut lms_pass: Option<String> = None;
+    let mut rescan: bool = false;
+    let mut net_timeout: Option<u64> = None;
+    let mut discover: bool = false;
+    let mut no_progress: bool = false;
+    let mut progress_json: bool = false;
+    let mut watch: bool = false;
+    let mut watch_delay: Option<u64> = None;
+    let mut upload_on_change: bool = false;
+    let mut fix: bool = false;
+    let mut preserve_mod_times: bool = false;
+    let mut all: bool = false;
+    let mut daemon: bool = false;
+    let mut interval: Option<String> = None;
+    let mut force_unlock: bool = false;
+    let mut max_consecutive_failures: Option<usize> = None;
+    let mut status_file: Option<String> = None;
+    let mut seed: Option<String> = None;
+    let mut count: Option<usize> = None;
+    let mut out: Option<String> = None;
+    let mut clusters: Option<usize> = None;
+    let mut metric: Option<String> = None;
+    let mut unicode_form: Option<String> = None;
+    let mut csv: bool = false;
+    let mut absolute: bool = false;
+    let mut files_from: Option<String> = None;
+    let mut remove_paths: Vec<String> = Vec::new();
+    let mut force: bool = false;
+    let mut refresh_stale: bool = false;
+    let mut ext: Option<String> = None;
+    let mut exclude_ext: Option<String> = None;
+    let mut extra_ext: Option<String> = None;
+    let mut order: Option<String> = None;
+    let mut min_duration: Option<u32> = None;
+    let mut max_duration: Option<u32> = None;
+    let mut follow_symlinks: bool = false;
+    let mut include_hidden: bool = false;
+    let mut scan_cache: Option<String> = None;
+    let mut since: Option<String> = None;
+    let mut no_backup: bool = false;
+    let mut max_remove: Option<usize> = None;
+    let mut force_remove: bool = false;
+    let mut group: bool = false;
+    let mut output_json: bool = false;
+    let mut clear: bool = false;
+    let mut backups: Option<usize> = None;
+    let mut notify_url: Option<String> = None;
+    let mut notify_interval: Option<u64> = None;
+    let mut notify_failures: bool = false;
+    let mut max_num_files: Option<usize> = None;
     let mut music_paths: Vec<PathBuf> = Vec::new();
-    let mut max_threads: usize = 0;
+    let mut max_threads: Option<usize> = None;
+    let mut reserve_cores: Option<usize> = None;
+    let mut errors_file: Option<String> = None;
+    let mut max_errors: Option<usize> = None;
+    let mut max_tag_errors: Option<usize> = None;
+    let mut default_music_path = ".".to_string();
 
     match dirs::home_dir() {
         Some(path) => {
-            music_path = String::from(path.join("Music").to_string_lossy());
+            default_music_path = String::from(path.join("Music").to_string_lossy());
         }
         None => {}
     }
 
     {
         let config_file_help = format!("config file (default: {})", &config_file);
-        let music_path_help = format!("Music folder (default: {})", &music_path);
-        let db_path_help = format!("Database location (default: {})", &db_path);
-        let logging_help = format!("Log level; trace, debug, info, warn, error. (default: {})", logging);
-        let ignore_file_help = format!("File contains items to mark as ignored. (default: {})", ignore_file);
-        let lms_host_help = format!("LMS hostname or IP address (default: {})", &lms_host);
-        let description = format!("Bliss Analyser v{}", VERSION);
+        let music_path_help = format!("Music folder, may be repeated (default: {})", &default_music_path);
+        let log_format_help = format!("Log output format; text, json. (default: {})", DEFAULT_LOG_FORMAT);
+        let db_path_help = format!("Database location (default: {})", DEFAULT_DB);
+        let logging_help = format!("Log level; trace, debug, info, warn, error. (default: {})", DEFAULT_LOGGING);
+        let max_remove_help = format!("Don't remove non-existent tracks from DB if these are more than this percentage of all tracks, as a music folder may not be mounted (default: {}, used with analyse task)", db::DEFAULT_MAX_REMOVE_PERCENT);
+        let backups_help = format!("Number of database backups to keep (default: {})", db::DEFAULT_BACKUPS);
+        let ignore_file_help = format!("File contains items to mark as ignored. (default: {})", DEFAULT_IGNORE);
+        let lms_host_help = format!("LMS hostname or IP address (default: {})", DEFAULT_LMS_HOST);
+        let lms_json_help = format!("LMS JSONRPC port (default: {})", DEFAULT_LMS_JSON_PORT);
+        let lms_scheme_help = format!("LMS URL scheme; http, https (default: {})", DEFAULT_LMS_SCHEME);
+        let lms_path_help = format!("LMS JSONRPC path (default: {})", DEFAULT_LMS_PATH);
+        let net_timeout_help = format!("Network connect and read timeout, in seconds (default: {})", DEFAULT_NET_TIMEOUT);
+        let max_errors_help = format!("Maximum number of analysis failures to show, 0 for all (default: {})", analyse::MAX_ERRORS_TO_SHOW);
+        let max_tag_errors_help = format!("Maximum number of tag failures to show, 0 for all (default: {})", analyse::MAX_TAG_ERRORS_TO_SHOW);
+        let notify_interval_help = format!("Minimum seconds between progress notifications, 0 to send all (default: {})", notify::DEFAULT_INTERVAL);
+        let watch_delay_help = format!("Seconds without changes before analysing changed files (default: {})", DEFAULT_WATCH_DELAY);
+        let interval_help = format!("Time between analysis runs in daemon mode; e.g. 90s, 30m, 6h, 1d (default: {})", DEFAULT_INTERVAL);
+        let count_help = format!("Number of similar tracks to add to playlist (default: {})", DEFAULT_PLAYLIST_COUNT);
+        let out_help = format!("File to write playlist or album vectors to, or folder to write clusters to (default: {}, {}, or {})", DEFAULT_PLAYLIST_FILE, DEFAULT_ALBUMS_FILE, DEFAULT_CLUSTER_FOLDER);
+        let unicode_form_help = format!("Unicode form of paths stored in DB; nfc or nfd. (default: {})", DEFAULT_UNICODE_FORM);
+        let metric_help = format!("Distance metric used to find similar tracks; {}. (default: {})", distance::METRIC_NAMES, DEFAULT_METRIC);
+        let clusters_help = format!("Number of clusters to group tracks into (default: {})", DEFAULT_CLUSTERS);
+        let ext_help = format!("Comma separated list of file extensions to analyse (default: {})", analyse::DEFAULT_EXTENSIONS.join(","));
+        let order_help = format!("Order in which new files are analysed; {}. (default: {})", analyse::ORDER_NAMES, DEFAULT_ORDER);
+        let task_help = format!("Task to perform; {}.", TASKS.join(", "));
+        let skip_marker_help = format!("Skip folders containing a file with this name. May be repeated (default: {})", analyse::DEFAULT_SKIP_MARKER);
+        let description = format!("Bliss Analyser v{}. Exit codes: {} success, {} some files failed or were not analysed, {} configuration error, {} database error, {} LMS or network error.",
+                                  VERSION, error::EXIT_SUCCESS, error::EXIT_PARTIAL, error::EXIT_CONFIG, error::EXIT_DB, error::EXIT_LMS);
 
         // arg_parse.refer 'borrows' db_path, etc, and can only have one
         // borrow per scope, hence this section is enclosed in { }
         let mut arg_parse = ArgumentParser::new();
         arg_parse.set_description(&description);
         arg_parse.refer(&mut config_file).add_option(&["-c", "--config"], Store, &config_file_help);
-        arg_parse.refer(&mut music_path).add_option(&["-m", "--music"], Store, &music_path_help);
-        arg_parse.refer(&mut db_path).add_option(&["-d", "--db"], Store, &db_path_help);
-        arg_parse.refer(&mut logging).add_option(&["-l", "--logging"], Store, &logging_help);
+        arg_parse.refer(&mut music_path).add_option(&["-m", "--music"], Collect, &music_path_help);
+        arg_parse.refer(&mut db_path).add_option(&["-d", "--db"], StoreOption, &db_path_help);
+        arg_parse.refer(&mut logging).add_option(&["-l", "--logging"], StoreOption, &logging_help);
+        arg_parse.refer(&mut log_file).add_option(&["--log-file"], StoreOption, "File to write log output to, in addition to screen");
+        arg_parse.refer(&mut log_format).add_option(&["--log-format"], StoreOption, &log_format_help);
+        arg_parse.refer(&mut quiet).add_option(&["-q", "--quiet"], StoreTrue, "Only log warnings, errors, and summary of analysis. Progress bar is not shown");
+        arg_parse.refer(&mut no_color).add_option(&["--no-color"], StoreTrue, "Never use colour codes in output. Also set if NO_COLOR environment variable is set");
         arg_parse.refer(&mut keep_old).add_option(&["-k", "--keep-old"], StoreTrue, "Don't remove files from DB if they don't exist (used with analyse task)");
-        arg_parse.refer(&mut dry_run).add_option(&["-r", "--dry-run"], StoreTrue, "Dry run, only show what needs to be done (used with analyse task)");
-        arg_parse.refer(&mut ignore_file).add_option(&["-i", "--ignore"], Store, &ignore_file_help);
-        arg_parse.refer(&mut lms_host).add_option(&["-L", "--lms"], Store, &lms_host_help);
-        arg_parse.refer(&mut max_num_files).add_option(&["-n", "--numfiles"], Store, "Maximum number of files to analyse");
-        arg_parse.refer(&mut max_threads).add_option(&["-t", "--threads"], Store, "Maximum number of threads to use for analysis");
-        arg_parse.refer(&mut task).add_argument("task", Store, "Task to perform; analyse, tags, ignore, upload, stopmixer.");
+        arg_parse.refer(&mut max_remove).add_option(&["--max-remove"], StoreOption, &max_remove_help);
+        arg_parse.refer(&mut force_remove).add_option(&["--force-remove"], StoreTrue, "Remove non-existent tracks from DB, even if more than --max-remove percent of tracks (used with analyse task)");
+        arg_parse.refer(&mut moved_from).add_option(&["--moved-from"], Collect, "Tracks whose path starts with OLD have moved to NEW, given as OLD=NEW. May be repeated (used with analyse task)");
+        arg_parse.refer(&mut dry_run).add_option(&["-r", "--dry-run"], StoreTrue, "Dry run, only show what needs to be done (used with analyse and sync-tags tasks)");
+        arg_parse.refer(&mut report_file).add_option(&["--report"], StoreOption, "File to write dry run report to, as JSON if name ends with '.json' - otherwise as text (used with analyse task)");
+        arg_parse.refer(&mut ignore_file).add_option(&["-i", "--ignore"], StoreOption, &ignore_file_help);
+        arg_parse.refer(&mut lms_host).add_option(&["-L", "--lms"], StoreOption, &lms_host_help);
+        arg_parse.refer(&mut lms_json_port).add_option(&["-J", "--json"], StoreOption, &lms_json_help);
+        arg_parse.refer(&mut lms_scheme).add_option(&["--lms-scheme"], StoreOption, &lms_scheme_help);
+        arg_parse.refer(&mut lms_path).add_option(&["--lms-path"], StoreOption, &lms_path_help);
+        arg_parse.refer(&mut lms_user).add_option(&["--lms-user"], StoreOption, "LMS username, if LMS is password protected");
+        arg_parse.refer(&mut lms_pass).add_option(&["--lms-pass"], StoreOption, "LMS password, if LMS is password protected");
+        arg_parse.refer(&mut discover).add_option(&["--discover"], StoreTrue, "Discover LMS on local network, instead of using configured host and port");
+        arg_parse.refer(&mut notify_url).add_option(&["--notify-url"], StoreOption, "URL to POST analysis progress notifications to (used with analyse task)");
+        arg_parse.refer(&mut notify_interval).add_option(&["--notify-interval"], StoreOption, &notify_interval_help);
+        arg_parse.refer(&mut notify_failures).add_option(&["--notify-failures"], StoreTrue, "Send a notification for each file that fails to be analysed");
+        arg_parse.refer(&mut net_timeout).add_option(&["--net-timeout"], StoreOption, &net_timeout_help);
+        arg_parse.refer(&mut rescan).add_option(&["--rescan"], StoreTrue, "Ask LMS to rescan after database has been uploaded (used with upload task)");
+        arg_parse.refer(&mut max_num_files).add_option(&["-n", "--numfiles"], StoreOption, "Maximum number of files to analyse");
+        arg_parse.refer(&mut max_threads).add_option(&["-t", "--threads"], StoreOption, "Maximum number of threads to use for analysis");
+        arg_parse.refer(&mut reserve_cores).add_option(&["--reserve-cores"], StoreOption, "Number of CPU cores to leave free during analysis");
+        arg_parse.refer(&mut errors_file).add_option(&["--errors-file"], StoreOption, "File to write complete list of failures to (used with analyse task)");
+        arg_parse.refer(&mut max_errors).add_option(&["--max-errors"], StoreOption, &max_errors_help);
+        arg_parse.refer(&mut max_tag_errors).add_option(&["--max-tag-errors"], StoreOption, &max_tag_errors_help);
+        arg_parse.refer(&mut no_progress).add_option(&["--no-progress"], StoreTrue, "Don't show progress bar, periodically log progress instead (used with analyse task)");
+        arg_parse.refer(&mut progress_json).add_option(&["--progress-json"], StoreTrue, "Write analysis progress to stderr as JSON, instead of showing progress bar (used with analyse task)");
+        arg_parse.refer(&mut watch).add_option(&["--watch"], StoreTrue, "Keep running, and analyse files as they are added or changed (used with analyse task)");
+        arg_parse.refer(&mut watch_delay).add_option(&["--watch-delay"], StoreOption, &watch_delay_help);
+        arg_parse.refer(&mut upload_on_change).add_option(&["--upload-on-change"], StoreTrue, "Upload database after changed files have been analysed (used with --watch)");
+        arg_parse.refer(&mut daemon).add_option(&["--daemon"], StoreTrue, "Keep running, and analyse new files at regular intervals (used with analyse task)");
+        arg_parse.refer(&mut interval).add_option(&["--interval"], StoreOption, &interval_help);
+        arg_parse.refer(&mut max_consecutive_failures).add_option(&["--max-consecutive-failures"], StoreOption, "Abort analysis after this many failures in a row (default: 0, never abort)");
+        arg_parse.refer(&mut status_file).add_option(&["--status-file"], StoreOption, "JSON file to write analysis status to (used with analyse task)");
+        arg_parse.refer(&mut no_backup).add_option(&["--no-backup"], StoreTrue, "Don't backup database before removing tracks from it, or upgrading it");
+        arg_parse.refer(&mut backups).add_option(&["--backups"], StoreOption, &backups_help);
+        arg_parse.refer(&mut force_unlock).add_option(&["--force-unlock"], StoreTrue, "Remove database lock file left by a previous run");
+        arg_parse.refer(&mut fix).add_option(&["--fix"], StoreTrue, "Remove tracks with invalid analysis from DB, so that they are re-analysed (used with verify task)");
+        arg_parse.refer(&mut preserve_mod_times).add_option(&["--preserve-mod-times"], StoreTrue, "Keep modification time of files whose tags are updated (used with sync-tags task)");
+        arg_parse.refer(&mut all).add_option(&["--all"], StoreTrue, "Un-ignore all tracks, instead of those listed in ignore file (used with un-ignore task)");
+        arg_parse.refer(&mut seed).add_option(&["--seed"], StoreOption, "Path of track, relative to music folder, to base playlist on (used with playlist task)");
+        arg_parse.refer(&mut count).add_option(&["--count"], StoreOption, &count_help);
+        arg_parse.refer(&mut out).add_option(&["--out"], StoreOption, &out_help);
+        arg_parse.refer(&mut metric).add_option(&["--metric"], StoreOption, &metric_help);
+        arg_parse.refer(&mut unicode_form).add_option(&["--unicode-form"], StoreOption, &unicode_form_help);
+        arg_parse.refer(&mut clusters).add_option(&["--clusters"], StoreOption, &clusters_help);
+        arg_parse.refer(&mut csv).add_option(&["--csv"], StoreTrue, "Write a CSV file mapping tracks to clusters, instead of a playlist per cluster (used with cluster task)");
+        arg_parse.refer(&mut force).add_option(&["--force"], StoreTrue, "Re-analyse files that are already in DB (used with analyse task)");
+        arg_parse.refer(&mut refresh_stale).add_option(&["--refresh-stale"], StoreTrue, "Re-analyse files analysed by an older version of bliss (used with analyse task)");
+        arg_parse.refer(&mut exclude).add_option(&["--exclude"], Collect, "Skip files and folders matching glob pattern, relative to music folder. May be repeated (used with analyse task)");
+        arg_parse.refer(&mut exclude_file).add_option(&["--exclude-file"], StoreOption, "File containing glob patterns of files and folders to skip, one per line (used with analyse task)");
+        arg_parse.refer(&mut ext).add_option(&["--ext"], StoreOption, &ext_help);
+        arg_parse.refer(&mut extra_ext).add_option(&["--extra-ext"], StoreOption, "Comma separated list of file extensions to analyse, in addition to the default (or --ext) list");
+        arg_parse.refer(&mut exclude_ext).add_option(&["--exclude-ext"], StoreOption, "Comma separated list of file extensions to not analyse");
+        arg_parse.refer(&mut order).add_option(&["--order"], StoreOption, &order_help);
+        arg_parse.refer(&mut min_duration).add_option(&["--min-duration"], StoreOption, "Skip files shorter than this many seconds (default: 0, no minimum)");
+        arg_parse.refer(&mut include_hidden).add_option(&["--include-hidden"], StoreTrue, "Analyse hidden files, and scan hidden folders");
+        arg_parse.refer(&mut follow_symlinks).add_option(&["--follow-symlinks"], StoreTrue, "Analyse symlinked files, and scan symlinked folders");
+        arg_parse.refer(&mut since).add_option(&["--since"], StoreOption, "Only analyse files modified within this time, e.g. 48h or 7d (used with analyse task)");
+        arg_parse.refer(&mut scan_cache).add_option(&["--scan-cache"], StoreOption, "File to cache folder listings in, so that unchanged folders are not re-read (used with analyse task)");
+        arg_parse.refer(&mut max_duration).add_option(&["--max-duration"], StoreOption, "Skip files longer than this many seconds (default: 0, no maximum)");
+        arg_parse.refer(&mut skip_markers).add_option(&["--skip-marker"], Collect, &skip_marker_help);
+        arg_parse.refer(&mut files_from).add_option(&["--files-from"], StoreOption, "Only analyse files listed in this file, or '-' for stdin (used with analyse task)");
+        arg_parse.refer(&mut remove_paths).add_option(&["--path"], Collect, "Path of file, or folder, relative to music folder, of tracks to remove or list. May be repeated (used with remove and list-ignored tasks)");
+        arg_parse.refer(&mut group).add_option(&["--group"], StoreTrue, "List number of tracks per top-level folder, instead of each track (used with list-ignored task)");
+        arg_parse.refer(&mut output_json).add_option(&["--output-json"], StoreTrue, "Output list as JSON (used with list-ignored task)");
+        arg_parse.refer(&mut clear).add_option(&["--clear"], StoreTrue, "Un-ignore listed tracks (used with list-ignored task)");
+        arg_parse.refer(&mut absolute).add_option(&["--absolute"], StoreTrue, "Show absolute paths, rather than paths relative to music folder (used with find-orphans task)");
+        arg_parse.refer(&mut task).add_argument("task", Store, &task_help);
         arg_parse.parse_args_or_exit();
     }
 
+    // Load config file before initialising logging, as this may specify log level.
+    // Values given on the command-line take precedence over those in config file.
+    let mut config: Option<Ini> = None;
+    let mut config_error: Option<String> = None;
+    if !config_file.is_empty() {
+        let path = PathBuf::from(&config_file);
+        if path.exists() && path.is_file() {
+            if config_file.to_ascii_lowercase().ends_with(".toml") {
+                match config::load_toml(&config_file, TOP_LEVEL_INI_TAG) {
+                    Ok(ini) => { config = Some(ini); }
+                    Err(e) => { config_error = Some(e); }
+                }
+            } else {
+                let mut ini = Ini::new();
+                match ini.load(&config_file) {
+                    Ok(_) => { config = Some(ini); }
+                    Err(e) => { config_error = Some(e); }
+                }
+            }
+        }
+    }
+
+    let mut logging = logging.or(config_str(&config, "logging")).unwrap_or(DEFAULT_LOGGING.to_string());
+    let quiet = quiet || config_bool(&config, "quiet");
+    // See https://no-color.org - any non-empty value disables colour
+    let no_color = no_color || config_bool(&config, "no_color") || std::env::var_os("NO_COLOR").map_or(false, |val| !val.is_empty());
+    if quiet {
+        logging = String::from("warn");
+    }
+    let log_file = log_file.or(config_str(&config, "log_file")).unwrap_or_default();
+    let log_format = log_format.or(config_str(&config, "log_format")).unwrap_or(DEFAULT_LOG_FORMAT.to_string());
+    if !log_format.eq_ignore_ascii_case("text") && !log_format.eq_ignore_ascii_case("json") {
+        eprintln!("Invalid log format ({}) supplied", log_format);
+        process::exit(error::EXIT_CONFIG);
+    }
+    let json_log = log_format.eq_ignore_ascii_case("json");
+    logging::set_json(json_log);
     if !(logging.eq_ignore_ascii_case("trace") || logging.eq_ignore_ascii_case("debug") || logging.eq_ignore_ascii_case("info")
         || logging.eq_ignore_ascii_case("warn") || logging.eq_ignore_ascii_case("error")) {
-        logging = String::from("info");
+        logging = String::from(DEFAULT_LOGGING);
     }
-    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().filter_or("XXXXXXXX", logging));
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().filter_or("XXXXXXXX", logging.as_str()));
     builder.filter(Some("bliss_audio"), LevelFilter::Error);
-    builder.format(|buf, record| {
-        writeln!(buf, "[{} {:.1}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.args())
+    if no_color {
+        builder.write_style(env_logger::WriteStyle::Never);
+    }
+    if logging.eq_ignore_ascii_case("warn") {
+        // Still show summary of analysis, so that scripts only need to check warnings and errors
+        builder.filter(Some(logging::SUMMARY), LevelFilter::Info);
+    }
+    builder.format(move |buf, record| {
+        if json_log {
+            writeln!(buf, "{}", logging::format_json(record.level(), record.target(), &record.args().to_string(), None))
+        } else {
+            writeln!(buf, "[{} {:.1}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), record.level(), record.args())
+        }
     });
+    if !log_file.is_empty() {
+        if let Err(e) = logging::init_file(&log_file) {
+            eprintln!("Failed to open log file ({}). {}", log_file, e);
+            process::exit(error::EXIT_CONFIG);
+        }
+        builder.target(env_logger::Target::Pipe(Box::new(logging::Tee)));
+    }
     builder.init();
 
     if task.is_empty() {
-        log::error!("No task specified, please choose from; analyse, tags, ignore, upload");
-        process::exit(-1);
+        log::error!("No task specified, please choose from; {}", TASKS.join(", "));
+        process::exit(error::EXIT_CONFIG);
     }
 
-    if !task.eq_ignore_ascii_case("analyse") && !task.eq_ignore_ascii_case("tags") && !task.eq_ignore_ascii_case("ignore")
-        && !task.eq_ignore_ascii_case("upload") && !task.eq_ignore_ascii_case("stopmixer") {
+    if !TASKS.iter().any(|t| task.eq_ignore_ascii_case(t)) {
         log::error!("Invalid task ({}) supplied", task);
-        process::exit(-1);
+        process::exit(error::EXIT_CONFIG);
     }
 
-    if !config_file.is_empty() {
-        let path = PathBuf::from(&config_file);
-        if path.exists() && path.is_file() {
-            let mut config = Ini::new();
-            match config.load(&config_file) {
-                Ok(_) => {
-                    let path_keys: [&str; 5] = ["music", "music_1", "music_2", "music_3", "music_4"];
-                    for key in &path_keys {
-                        match config.get(TOP_LEVEL_INI_TAG, key) {
-                            Some(val) => { music_paths.push(PathBuf::from(&val)); }
-                            None => { }
-                        }
-                    }
-                    match config.get(TOP_LEVEL_INI_TAG, "db") {
-                        Some(val) => { db_path = val; }
-                        None => { }
-                    }
-                    match config.get(TOP_LEVEL_INI_TAG, "lms") {
-                        Some(val) => { lms_host = val; }
-                        None => { }
-                    }
-                    match config.get(TOP_LEVEL_INI_TAG, "ignore") {
-                        Some(val) => { ignore_file = val; }
-                        None => { }
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to load config file. {}", e);
-                    process::exit(-1);
-                }
+    if let Some(e) = config_error {
+        log::error!("Failed to load config file. {}", e);
+        process::exit(error::EXIT_CONFIG);
+    }
+
+    if music_path.is_empty() {
+        for val in config_list(&config, "music") {
+            music_paths.push(PathBuf::from(&val));
+        }
+    } else {
+        for path in &music_path {
+            music_paths.push(PathBuf::from(path));
+        }
+    }
+    let db_path = db_path.or(config_str(&config, "db")).unwrap_or(DEFAULT_DB.to_string());
+    let ignore_file = ignore_file.or(config_str(&config, "ignore")).unwrap_or(DEFAULT_IGNORE.to_string());
+    let mut lms_host = lms_host.or(config_str(&config, "lms")).unwrap_or(DEFAULT_LMS_HOST.to_string());
+    let mut lms_json_port = lms_json_port.or(config_val(&config, "json")).unwrap_or(DEFAULT_LMS_JSON_PORT);
+    let lms_scheme = lms_scheme.or(config_str(&config, "lms_scheme")).unwrap_or(DEFAULT_LMS_SCHEME.to_string());
+    let mut lms_path = lms_path.or(config_str(&config, "lms_path")).unwrap_or(DEFAULT_LMS_PATH.to_string());
+    let lms_user = lms_user.or(config_str(&config, "lms_user")).unwrap_or_default();
+    let lms_pass = lms_pass.or(config_str(&config, "lms_pass")).unwrap_or_default();
+    let notify_url = notify_url.or(config_str(&config, "notify_url")).unwrap_or_default();
+    let notify_interval = notify_interval.or(config_val(&config, "notify_interval")).unwrap_or(notify::DEFAULT_INTERVAL);
+    let notify_failures = notify_failures || config_bool(&config, "notify_failures");
+    let net_timeout = net_timeout.or(config_val(&config, "net_timeout")).unwrap_or(DEFAULT_NET_TIMEOUT);
+    let max_num_files = max_num_files.or(config_val(&config, "numfiles")).unwrap_or(0);
+    let mut max_threads = max_threads.or(config_val(&config, "threads")).unwrap_or(0);
+    let reserve_cores = reserve_cores.or(config_val(&config, "reserve_cores")).unwrap_or(0);
+    let errors_file = errors_file.or(config_str(&config, "errors_file")).unwrap_or_default();
+    let max_errors = max_errors.or(config_val(&config, "max_errors")).unwrap_or(analyse::MAX_ERRORS_TO_SHOW);
+    let max_tag_errors = max_tag_errors.or(config_val(&config, "max_tag_errors")).unwrap_or(analyse::MAX_TAG_ERRORS_TO_SHOW);
+    let keep_old = keep_old || config_bool(&config, "keep_old");
+    let max_remove = max_remove.or(config_val(&config, "max_remove")).unwrap_or(db::DEFAULT_MAX_REMOVE_PERCENT);
+    let force_remove = force_remove || config_bool(&config, "force_remove");
+    if moved_from.is_empty() {
+        moved_from = config_list(&config, "moved_from");
+    }
+    let mut moved_paths: Vec<(String, String)> = Vec::new();
+    for mapping in &moved_from {
+        match mapping.split_once('=') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => {
+                moved_paths.push((from.trim().to_string(), to.trim().to_string()));
+            }
+            _ => {
+                log::error!("Invalid moved from mapping ({}), must be OLD=NEW", mapping);
+                process::exit(error::EXIT_CONFIG);
             }
         }
     }
+    let dry_run = dry_run || config_bool(&config, "dry_run");
+    let report_file = report_file.or(config_str(&config, "report")).unwrap_or_default();
+    let preserve_mod_times = preserve_mod_times || config_bool(&config, "preserve_mod_times");
+    let discover = discover || config_bool(&config, "discover");
+    let rescan = rescan || config_bool(&config, "rescan");
+    let no_progress = no_progress || config_bool(&config, "no_progress");
+    let progress_json = progress_json || config_bool(&config, "progress_json");
+    let watch = watch || config_bool(&config, "watch");
+    let watch_delay = watch_delay.or(config_val(&config, "watch_delay")).unwrap_or(DEFAULT_WATCH_DELAY);
+    let upload_on_change = upload_on_change || config_bool(&config, "upload_on_change");
+    let daemon = daemon || config_bool(&config, "daemon");
+    let status_file = status_file.or(config_str(&config, "status_file")).unwrap_or_default();
+    let max_consecutive_failures = max_consecutive_failures.or(config_val(&config, "max_consecutive_failures")).unwrap_or(0);
+    let interval = interval.or(config_str(&config, "interval")).unwrap_or(DEFAULT_INTERVAL.to_string());
+    let seed = seed.or(config_str(&config, "seed")).unwrap_or_default();
+    let count = count.or(config_val(&config, "count")).unwrap_or(DEFAULT_PLAYLIST_COUNT);
+    let out = out.or(config_str(&config, "out")).unwrap_or(if task.eq_ignore_ascii_case("cluster") {
+        DEFAULT_CLUSTER_FOLDER
+    } else if task.eq_ignore_ascii_case("album-vectors") {
+        DEFAULT_ALBUMS_FILE
+    } else {
+        DEFAULT_PLAYLIST_FILE
+    }.to_string());
+    let clusters = clusters.or(config_val(&config, "clusters")).unwrap_or(DEFAULT_CLUSTERS);
+    let metric = metric.or(config_str(&config, "metric")).unwrap_or(DEFAULT_METRIC.to_string());
+    let unicode_form = unicode_form.or(config_str(&config, "unicode_form")).unwrap_or(DEFAULT_UNICODE_FORM.to_string());
+    let csv = csv || config_bool(&config, "csv");
+    let absolute = absolute || config_bool(&config, "absolute");
+    let files_from = files_from.or(config_str(&config, "files_from")).unwrap_or_default();
+    let force = force || config_bool(&config, "force");
+    let refresh_stale = refresh_stale || config_bool(&config, "refresh_stale");
+    let force_unlock = force_unlock || config_bool(&config, "force_unlock");
+    let fix = fix || config_bool(&config, "fix");
+    let all = all || config_bool(&config, "all");
+    let group = group || config_bool(&config, "group");
+    let output_json = output_json || config_bool(&config, "output_json");
+    let clear = clear || config_bool(&config, "clear");
+    if remove_paths.is_empty() {
+        remove_paths = config_list(&config, "path");
+    }
+    if exclude.is_empty() {
+        exclude = config_list(&config, "exclude");
+    }
+    if let Some(file) = exclude_file.or(config_str(&config, "exclude_file")) {
+        exclude.extend(check(analyse::read_exclude_file(&file)));
+    }
+    let exclude_patterns = exclude.clone();
+    let exclude = check(analyse::build_exclude(&exclude));
+    let ext = ext.or(config_str(&config, "ext")).unwrap_or_default();
+    let exclude_ext = exclude_ext.or(config_str(&config, "exclude_ext")).unwrap_or_default();
+    let extra_ext = extra_ext.or(config_str(&config, "extra_extensions")).unwrap_or_default();
+    let extensions = check(analyse::build_extensions(&ext, &extra_ext, &exclude_ext));
+    let order = order.or(config_str(&config, "order")).unwrap_or(DEFAULT_ORDER.to_string());
+    let min_duration = min_duration.or(config_val(&config, "min_duration")).unwrap_or(0);
+    let max_duration = max_duration.or(config_val(&config, "max_duration")).unwrap_or(0);
+    let follow_symlinks = follow_symlinks || config_bool(&config, "follow_symlinks");
+    let include_hidden = include_hidden || config_bool(&config, "include_hidden");
+    let scan_cache = scan_cache.or(config_str(&config, "scan_cache")).unwrap_or_default();
+    let since = since.or(config_str(&config, "since")).unwrap_or_default();
+    let no_backup = no_backup || config_bool(&config, "no_backup");
+    let backups = backups.or(config_val(&config, "backups")).unwrap_or(db::DEFAULT_BACKUPS);
+    if skip_markers.is_empty() {
+        skip_markers = config_list(&config, "skip_marker");
+    }
+    if skip_markers.is_empty() {
+        skip_markers.push(analyse::DEFAULT_SKIP_MARKER.to_string());
+    }
+    for marker in &skip_markers {
+        if marker.is_empty() || marker.contains('/') || marker.contains('\\') {
+            log::error!("Invalid skip marker ({}), must be a file name", marker);
+            process::exit(error::EXIT_CONFIG);
+        }
+    }
 
     if music_paths.is_empty() {
-        music_paths.push(PathBuf::from(&music_path));
+        music_paths.push(PathBuf::from(&default_music_path));
+    }
+    check_music_paths(&mut music_paths);
+    // Allow paths longer than 260 characters on Windows
+    let music_paths: Vec<PathBuf> = music_paths.iter().map(|p| db::long_path(p)).collect();
+
+    if !report_file.is_empty() && !dry_run {
+        log::error!("Report can only be written for a dry run");
+        process::exit(error::EXIT_CONFIG);
+    }
+    if watch && dry_run {
+        log::error!("Dry run cannot be used with watch");
+        process::exit(error::EXIT_CONFIG);
+    }
+    if force && (watch || daemon) {
+        log::error!("Watch and daemon mode cannot be used with --force");
+        process::exit(error::EXIT_CONFIG);
+    }
+    if !files_from.is_empty() && !task.eq_ignore_ascii_case("remove") && (watch || daemon || dry_run) {
+        log::error!("Watch, daemon mode, and dry run cannot be used with --files-from");
+        process::exit(error::EXIT_CONFIG);
+    }
+    if task.eq_ignore_ascii_case("retry") && (watch || daemon || dry_run) {
+        log::error!("Watch, daemon mode, and dry run cannot be used with retry");
+        process::exit(error::EXIT_CONFIG);
+    }
+    if daemon && (watch || dry_run) {
+        log::error!("Daemon mode cannot be used with watch or dry run");
+        process::exit(error::EXIT_CONFIG);
+    }
+    let interval_secs = match daemon::parse_interval(&interval) {
+        Some(secs) => secs,
+        None => {
+            log::error!("Invalid interval ({}) supplied", interval);
+            process::exit(error::EXIT_CONFIG);
+        }
+    };
+    if max_remove > 100 {
+        log::error!("Invalid max remove ({}) supplied, must be a percentage", max_remove);
+        process::exit(error::EXIT_CONFIG);
+    }
+    let since_secs = if since.is_empty() {
+        0
+    } else {
+        match daemon::parse_interval(&since) {
+            Some(secs) => secs,
+            None => {
+                log::error!("Invalid since ({}) supplied", since);
+                process::exit(error::EXIT_CONFIG);
+            }
+        }
+    };
+    if watch_delay == 0 {
+        log::error!("Invalid watch delay ({}) supplied", watch_delay);
+        process::exit(error::EXIT_CONFIG);
+    }
+    if net_timeout == 0 {
+        log::error!("Invalid network timeout ({}) supplied", net_timeout);
+        process::exit(error::EXIT_CONFIG);
+    }
+    if !lms_scheme.eq_ignore_ascii_case("http") && !lms_scheme.eq_ignore_ascii_case("https") {
+        log::error!("Invalid LMS scheme ({}) supplied", lms_scheme);
+        process::exit(error::EXIT_CONFIG);
     }
+    if task.eq_ignore_ascii_case("playlist") && seed.is_empty() {
+        log::error!("No seed track supplied");
+        process::exit(error::EXIT_CONFIG);
+    }
+    if count == 0 {
+        log::error!("Invalid count ({}) supplied", count);
+        process::exit(error::EXIT_CONFIG);
+    }
+    let metric = match distance::Metric::from_name(&metric) {
+        Some(m) => m,
+        None => {
+            log::error!("Invalid metric ({}) supplied", metric);
+            process::exit(error::EXIT_CONFIG);
+        }
+    };
+    if !unicode_form.eq_ignore_ascii_case("nfc") && !unicode_form.eq_ignore_ascii_case("nfd") {
+        log::error!("Invalid Unicode form ({}) supplied", unicode_form);
+        process::exit(error::EXIT_CONFIG);
+    }
+    db::set_nfd_paths(unicode_form.eq_ignore_ascii_case("nfd"));
+    db::set_num_backups(if no_backup { 0 } else { backups });
+    let order = match analyse::Order::from_name(&order) {
+        Some(o) => o,
+        None => {
+            log::error!("Invalid order ({}) supplied", order);
+            process::exit(error::EXIT_CONFIG);
+        }
+    };
+    if max_duration > 0 && min_duration > max_duration {
+        log::error!("Minimum duration ({}) is larger than maximum duration ({})", min_duration, max_duration);
+        process::exit(error::EXIT_CONFIG);
+    }
+    if clusters == 0 {
+        log::error!("Invalid number of clusters ({}) supplied", clusters);
+        process::exit(error::EXIT_CONFIG);
+    }
+    if !lms_path.starts_with("/") {
+        lms_path = format!("/{}", lms_path);
+    }
+    // LMS is only contacted by these tasks, or when watching with --upload-on-change
+    let uses_lms = task.eq_ignore_ascii_case("upload") || task.eq_ignore_ascii_case("stopmixer") || task.eq_ignore_ascii_case("doctor")
+        || (task.eq_ignore_ascii_case("analyse") && watch && upload_on_change);
+    if discover && uses_lms {
+        match upload::discover() {
+            Some((host, port)) => {
+                lms_host = host;
+                lms_json_port = port;
+            }
+            None => { log::warn!("No LMS found, using {}:{}", lms_host, lms_json_port); }
+        }
+    }
+    let resolved: Vec<(&str, Vec<String>)> = if task.eq_ignore_ascii_case("dump-config") {
+        vec![
+            ("music", music_paths.iter().map(|p| String::from(p.to_string_lossy())).collect()),
+            ("db", vec![db_path.clone()]),
+            ("logging", vec![logging.clone()]),
+            ("quiet", vec![quiet.to_string()]),
+            ("no_color", vec![no_color.to_string()]),
+            ("log_file", vec![log_file.clone()]),
+            ("log_format", vec![log_format.to_ascii_lowercase()]),
+            ("ignore", vec![ignore_file.clone()]),
+            ("lms", vec![lms_host.clone()]),
+            ("json", vec![lms_json_port.to_string()]),
+            ("lms_scheme", vec![lms_scheme.to_ascii_lowercase()]),
+            ("lms_path", vec![lms_path.clone()]),
+            ("lms_user", vec![lms_user.clone()]),
+            ("lms_pass", vec![if lms_pass.is_empty() { String::new() } else { String::from("********") }]),
+            ("discover", vec![discover.to_string()]),
+            ("net_timeout", vec![net_timeout.to_string()]),
+            ("rescan", vec![rescan.to_string()]),
+            ("notify_url", vec![notify_url.clone()]),
+            ("notify_interval", vec![notify_interval.to_string()]),
+            ("notify_failures", vec![notify_failures.to_string()]),
+            ("numfiles", vec![max_num_files.to_string()]),
+            ("threads", vec![max_threads.to_string()]),
+            ("reserve_cores", vec![reserve_cores.to_string()]),
+            ("errors_file", vec![errors_file.clone()]),
+            ("max_errors", vec![max_errors.to_string()]),
+            ("max_tag_errors", vec![max_tag_errors.to_string()]),
+            ("keep_old", vec![keep_old.to_string()]),
+            ("max_remove", vec![max_remove.to_string()]),
+            ("force_remove", vec![force_remove.to_string()]),
+            ("moved_from", moved_from.clone()),
+            ("dry_run", vec![dry_run.to_string()]),
+            ("report", vec![report_file.clone()]),
+            ("no_progress", vec![no_progress.to_string()]),
+            ("progress_json", vec![progress_json.to_string()]),
+            ("watch", vec![watch.to_string()]),
+            ("watch_delay", vec![watch_delay.to_string()]),
+            ("upload_on_change", vec![upload_on_change.to_string()]),
+            ("daemon", vec![daemon.to_string()]),
+            ("interval", vec![interval.clone()]),
+            ("max_consecutive_failures", vec![max_consecutive_failures.to_string()]),
+            ("status_file", vec![status_file.clone()]),
+            ("force_unlock", vec![force_unlock.to_string()]),
+            ("fix", vec![fix.to_string()]),
+            ("preserve_mod_times", vec![preserve_mod_times.to_string()]),
+            ("all", vec![all.to_string()]),
+            ("seed", vec![seed.clone()]),
+            ("count", vec![count.to_string()]),
+            ("out", vec![out.clone()]),
+            ("metric", vec![metric.name().to_string()]),
+            ("unicode_form", vec![unicode_form.to_ascii_lowercase()]),
+            ("clusters", vec![clusters.to_string()]),
+            ("csv", vec![csv.to_string()]),
+            ("force", vec![force.to_string()]),
+            ("refresh_stale", vec![refresh_stale.to_string()]),
+            ("exclude", exclude_patterns.clone()),
+            ("ext", vec![ext.clone()]),
+            ("extra_extensions", vec![extra_ext.clone()]),
+            ("exclude_ext", vec![exclude_ext.clone()]),
+            ("order", vec![order.name().to_string()]),
+            ("min_duration", vec![min_duration.to_string()]),
+            ("max_duration", vec![max_duration.to_string()]),
+            ("include_hidden", vec![include_hidden.to_string()]),
+            ("follow_symlinks", vec![follow_symlinks.to_string()]),
+            ("scan_cache", vec![scan_cache.clone()]),
+            ("since", vec![since.clone()]),
+            ("no_backup", vec![no_backup.to_string()]),
+            ("backups", vec![backups.to_string()]),
+            ("skip_marker", skip_markers.clone()),
+            ("files_from", vec![files_from.clone()]),
+            ("path", remove_paths.clone()),
+            ("group", vec![group.to_string()]),
+            ("output_json", vec![output_json.to_string()]),
+            ("clear", vec![clear.to_string()]),
+            ("absolute", vec![absolute.to_string()]),
+        ]
+    } else {
+        Vec::new()
+    };
+    let lms = upload::Lms {
+        host: lms_host,
+        json_port: lms_json_port,
+        scheme: lms_scheme.to_ascii_lowercase(),
+        path: lms_path,
+        user: lms_user,
+        pass: lms_pass,
+        agent: upload::agent(net_timeout),
+    };
 
-    if task.eq_ignore_ascii_case("stopmixer") {
-        upload::stop_mixer(&lms_host);
+    if task.eq_ignore_ascii_case("dump-config") {
+        dump_config(&resolved);
+    } else if task.eq_ignore_ascii_case("stopmixer") {
+        check(upload::stop_mixer(&lms));
+    } else if task.eq_ignore_ascii_case("doctor") {
+        check(doctor::run(&db_path, &music_paths, &lms));
     } else {
         if db_path.len() < 3 {
             log::error!("Invalid DB path ({}) supplied", db_path);
-            process::exit(-1);
+            process::exit(error::EXIT_CONFIG);
         }
 
         let path = PathBuf::from(&db_path);
         if path.exists() && !path.is_file() {
             log::error!("DB path ({}) is not a file", db_path);
-            process::exit(-1);
+            process::exit(error::EXIT_CONFIG);
         }
 
-        if task.eq_ignore_ascii_case("upload") {
-            if path.exists() {
-                upload::upload_db(&db_path, &lms_host);
+        // Only allow one instance to update DB at a time
+        let analysing = (task.eq_ignore_ascii_case("analyse") || task.eq_ignore_ascii_case("retry-failed") || task.eq_ignore_ascii_case("retry")) && !dry_run;
+        if analysing || task.eq_ignore_ascii_case("tags") || task.eq_ignore_ascii_case("ignore") || task.eq_ignore_ascii_case("un-ignore")
+            || task.eq_ignore_ascii_case("normalise") || (task.eq_ignore_ascii_case("remove") && !dry_run)
+            || (task.eq_ignore_ascii_case("verify") && fix) || (task.eq_ignore_ascii_case("list-ignored") && clear && !dry_run) {
+            check(lock::acquire(&db_path, force_unlock));
+            if analysing {
+                analyse::stop_on_signal();
             } else {
+                lock::release_on_signal();
+            }
+        }
+
+        if task.eq_ignore_ascii_case("upload") || task.eq_ignore_ascii_case("info") || task.eq_ignore_ascii_case("verify") || task.eq_ignore_ascii_case("playlist")
+            || task.eq_ignore_ascii_case("cluster") || task.eq_ignore_ascii_case("album-vectors") || task.eq_ignore_ascii_case("list-ignored") || task.eq_ignore_ascii_case("un-ignore")
+            || task.eq_ignore_ascii_case("normalise") || task.eq_ignore_ascii_case("remove") {
+            if !path.exists() {
                 log::error!("DB ({}) does not exist", db_path);
-                process::exit(-1);
+                process::exit(error::EXIT_DB);
+            }
+            if task.eq_ignore_ascii_case("info") {
+                check(analyse::show_info(&db_path));
+            } else if task.eq_ignore_ascii_case("list-ignored") {
+                check(analyse::list_ignored(&db_path, &remove_paths, group, output_json, clear, dry_run));
+            } else if task.eq_ignore_ascii_case("normalise") {
+                check(analyse::normalise_paths(&db_path));
+            } else if task.eq_ignore_ascii_case("remove") {
+                let mut prefixes = remove_paths;
+                if !files_from.is_empty() {
+                    prefixes.extend(check(analyse::read_file_list(&files_from)).iter().map(|p| String::from(p.to_string_lossy())));
+                }
+                if prefixes.is_empty() {
+                    log::error!("No paths to remove, please use --path or --files-from");
+                    process::exit(error::EXIT_CONFIG);
+                }
+                check(analyse::remove_paths(&db_path, &prefixes, dry_run));
+            } else if task.eq_ignore_ascii_case("un-ignore") {
+                let ignore_path = PathBuf::from(&ignore_file);
+                if !all && !ignore_path.is_file() {
+                    log::error!("Ignore file ({}) does not exist, or is not a file", ignore_file);
+                    process::exit(error::EXIT_CONFIG);
+                }
+                check(analyse::un_ignore(&db_path, &ignore_path, all));
+            } else if task.eq_ignore_ascii_case("verify") {
+                check(analyse::verify(&db_path, fix));
+            } else if task.eq_ignore_ascii_case("playlist") {
+                check(playlist::create(&db_path, &music_paths, &seed, count, &out, metric));
+            } else if task.eq_ignore_ascii_case("cluster") {
+                check(cluster::create(&db_path, &music_paths, clusters, &out, csv));
+            } else if task.eq_ignore_ascii_case("album-vectors") {
+                check(album::create(&db_path, &out));
+            } else {
+                check(upload::upload_db(&db_path, &lms, rescan));
             }
         } else {
             for mpath in &music_paths {
                 if !mpath.exists() {
                     log::error!("Music path ({}) does not exist", mpath.to_string_lossy());
-                    process::exit(-1);
+                    process::exit(error::EXIT_CONFIG);
                 }
                 if !mpath.is_dir() {
                     log::error!("Music path ({}) is not a directory", mpath.to_string_lossy());
-                    process::exit(-1);
+                    process::exit(error::EXIT_CONFIG);
                 }
             }
 
             if task.eq_ignore_ascii_case("tags") {
-                analyse::read_tags(&db_path, &music_paths);
+                check(analyse::read_tags(&db_path, &music_paths));
+            } else if task.eq_ignore_ascii_case("sync-tags") {
+                if !path.exists() {
+                    log::error!("DB ({}) does not exist", db_path);
+                    process::exit(error::EXIT_DB);
+                }
+                if !dry_run {
+                    analyse::stop_on_signal();
+                }
+                check(analyse::sync_tags(&db_path, &music_paths, dry_run, preserve_mod_times));
+                exit_if_terminated();
+            } else if task.eq_ignore_ascii_case("find-orphans") {
+                if !path.exists() {
+                    log::error!("DB ({}) does not exist", db_path);
+                    process::exit(error::EXIT_DB);
+                }
+                check(analyse::find_orphans(&db_path, &music_paths, absolute));
             } else if task.eq_ignore_ascii_case("ignore") {
                 let ignore_path = PathBuf::from(&ignore_file);
                 if !ignore_path.exists() {
                     log::error!("Ignore file ({}) does not exist", ignore_file);
-                    process::exit(-1);
+                    process::exit(error::EXIT_CONFIG);
                 }
                 if !ignore_path.is_file() {
                     log::error!("Ignore file ({}) is not a file", ignore_file);
-                    process::exit(-1);
+                    process::exit(error::EXIT_CONFIG);
                 }
-                analyse::update_ignore(&db_path, &ignore_path);
+                check(analyse::update_ignore(&db_path, &ignore_path));
             } else {
-                analyse::analyse_files(&db_path, &music_paths, dry_run, keep_old, max_num_files, max_threads);
+                let num_cores = num_cpus::get();
+                if reserve_cores > 0 {
+                    if reserve_cores >= num_cores {
+                        log::error!("Cannot reserve {} CPU core(s), only {} available", reserve_cores, num_cores);
+                        process::exit(error::EXIT_CONFIG);
+                    }
+                    if max_threads == 0 || max_threads > num_cores - reserve_cores {
+                        max_threads = num_cores - reserve_cores;
+                    }
+                }
+                log::info!("Using {} of {} CPU core(s)", if max_threads == 0 { num_cores } else { max_threads }, num_cores);
+                let mut notifier = notify::Notifier::new(&notify_url, lms.agent.clone(), notify_interval, notify_failures);
+                let opts = analyse::Options {
+                    dry_run: dry_run,
+                    report_file: report_file,
+                    keep_old: keep_old,
+                    max_remove_percent: max_remove,
+                    force_remove: force_remove,
+                    moved_from: moved_paths,
+                    max_num_files: max_num_files,
+                    max_threads: max_threads,
+                    max_errors: max_errors,
+                    max_tag_errors: max_tag_errors,
+                    errors_file: errors_file,
+                    // Progress bar is only useful if output is to a terminal
+                    show_progress: !no_progress && !quiet && atty::is(atty::Stream::Stdout) && atty::is(atty::Stream::Stderr),
+                    progress_json: progress_json,
+                    max_consecutive_failures: max_consecutive_failures,
+                    status_file: status_file,
+                    retry_failed: task.eq_ignore_ascii_case("retry-failed"),
+                    exclude: exclude,
+                    exclude_patterns: exclude_patterns,
+                    force: force,
+                    skip_markers: skip_markers,
+                    refresh_stale: refresh_stale,
+                    extensions: extensions,
+                    order: order,
+                    min_duration: min_duration,
+                    max_duration: max_duration,
+                    follow_symlinks: follow_symlinks,
+                    include_hidden: include_hidden,
+                    scan_cache: scan_cache,
+                    since: since_secs,
+                };
+                if task.eq_ignore_ascii_case("retry") {
+                    let summary = check(analyse::retry_failures(&db_path, &music_paths, &opts, &mut notifier));
+                    exit_if_terminated();
+                    if summary.failed > 0 || summary.not_attempted() > 0 {
+                        lock::release();
+                        process::exit(error::EXIT_PARTIAL);
+                    }
+                } else if !files_from.is_empty() {
+                    let files = check(analyse::read_file_list(&files_from));
+                    let summary = check(analyse::analyse_listed(&db_path, &music_paths, &files, &opts, &mut notifier));
+                    exit_if_terminated();
+                    if summary.failed > 0 || summary.skipped > 0 || summary.not_attempted() > 0 {
+                        lock::release();
+                        process::exit(error::EXIT_PARTIAL);
+                    }
+                } else if daemon {
+                    check(daemon::run(&db_path, &music_paths, &opts, &mut notifier, interval_secs));
+                } else if watch {
+                    check(watch::watch(&db_path, &music_paths, &opts, &mut notifier, watch_delay, if upload_on_change { Some(&lms) } else { None }, rescan));
+                } else {
+                    let summary = check(analyse::analyse_files(&db_path, &music_paths, &opts, &mut notifier));
+                    // Non-zero exit code, so that scripts (e.g. cron) can detect incomplete analysis
+                    exit_if_terminated();
+                    if !dry_run && (summary.failed > 0 || summary.not_attempted() > 0) {
+                        lock::release();
+                        process::exit(error::EXIT_PARTIAL);
+                    }
+                }
             }
         }
+        lock::release();
     }
 }
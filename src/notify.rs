@@ -0,0 +1,124 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+/// Default minimum number of seconds between progress notifications.
+pub const DEFAULT_INTERVAL: u64 = 2;
+// Maximum number of failures listed in 'failures' notification
+const MAX_FAILURES_LISTED: usize = 5;
+
+pub const EVENT_START: &str = "start";
+pub const EVENT_PROGRESS: &str = "progress";
+pub const EVENT_FINISHED: &str = "finished";
+pub const EVENT_FAILED: &str = "failed";
+pub const EVENT_FAILURES: &str = "failures";
+
+/// Sends analysis progress to a webhook URL, if one has been configured.
+pub struct Notifier {
+    url: String,
+    agent: ureq::Agent,
+    last_sent: Option<Instant>,
+    interval: Duration,
+    send_failures: bool,
+}
+
+impl Notifier {
+    /// 'interval' is the minimum number of seconds between progress notifications, 0 to
+    /// send all. If 'send_failures' is set, a notification is sent for each file that
+    /// fails to be analysed.
+    pub fn new(url: &str, agent: ureq::Agent, interval: u64, send_failures: bool) -> Self {
+        Self {
+            url: url.to_string(),
+            agent: agent,
+            last_sent: None,
+            interval: Duration::from_secs(interval),
+            send_failures: send_failures,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.url.is_empty()
+    }
+
+    /// Send notification. Progress notifications are throttled, all others are
+    /// always sent.
+    pub fn send(&mut self, event: &str, message: &str, progress: u64) {
+        self.post(json!({
+            "event": event,
+            "message": message,
+            "progress": progress,
+        }));
+    }
+
+    /// Send progress notification, with estimated number of seconds remaining.
+    pub fn progress(&mut self, message: &str, progress: u64, eta: Option<u64>) {
+        let mut body = json!({
+            "event": EVENT_PROGRESS,
+            "message": message,
+            "progress": progress,
+        });
+        if let Some(eta) = eta {
+            body["eta"] = json!(eta);
+        }
+        self.post(body);
+    }
+
+    /// Send finished notification, with number of files analysed and failed. If there
+    /// were any errors then this is preceded by a 'failures' notification listing the
+    /// first few.
+    pub fn finished(&mut self, message: &str, analysed: usize, failed: usize, errors: &[(String, String)]) {
+        if !errors.is_empty() {
+            let mut lines: Vec<String> = errors.iter().take(MAX_FAILURES_LISTED).map(|(file, reason)| format!("{} - {}", file, reason)).collect();
+            if errors.len() > MAX_FAILURES_LISTED {
+                lines.push(format!("+ {} other(s)", errors.len() - MAX_FAILURES_LISTED));
+            }
+            self.post(json!({
+                "event": EVENT_FAILURES,
+                "message": lines.join("\n"),
+                "progress": 100,
+                "count": errors.len(),
+            }));
+        }
+        self.post(json!({
+            "event": EVENT_FINISHED,
+            "message": message,
+            "progress": 100,
+            "analysed": analysed,
+            "failed": failed,
+        }));
+    }
+
+    // Body is built via serde_json, so that file names are correctly escaped
+    fn post(&mut self, body: Value) {
+        if !self.enabled() {
+            return;
+        }
+        let now = Instant::now();
+        if body["event"] == EVENT_PROGRESS {
+            if let Some(last) = self.last_sent {
+                if now.duration_since(last) < self.interval {
+                    return;
+                }
+            }
+        }
+        self.last_sent = Some(now);
+
+        if let Err(e) = self.agent.post(&self.url).set("Content-Type", "application/json").send_string(&body.to_string()) {
+            log::debug!("Failed to send notification. {}", e);
+        }
+    }
+
+    /// Send notification for file that failed to be analysed, if enabled.
+    pub fn failure(&mut self, file: &str, reason: &str, progress: u64) {
+        if self.send_failures {
+            self.send(EVENT_FAILED, &format!("{} - {}", file, reason), progress);
+        }
+    }
+}
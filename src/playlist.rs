@@ -0,0 +1,84 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::db;
+use crate::distance::Metric;
+use crate::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+// DB paths are relative to the music folder containing the track, so use the first
+// folder in which the file exists.
+fn absolute_path(mpaths: &Vec<PathBuf>, file: &str) -> PathBuf {
+    for mpath in mpaths {
+        let path = db::fs_path(&mpath.canonicalize().unwrap_or(mpath.clone()), file);
+        if path.exists() {
+            return path;
+        }
+    }
+    match mpaths.first() {
+        Some(mpath) => db::fs_path(&mpath.canonicalize().unwrap_or(mpath.clone()), file),
+        None => PathBuf::from(file),
+    }
+}
+
+/// Tracks that can be written to a playlist; CUE tracks have no file of their own,
+/// and ignored tracks should not be in mixes.
+pub fn playable(track: &db::TrackAnalysis) -> bool {
+    !track.ignore && !track.file.contains(db::CUE_MARKER)
+}
+
+/// Write M3U playlist, using absolute paths.
+pub fn write_m3u(path: &Path, mpaths: &Vec<PathBuf>, files: &Vec<&str>) -> Result<(), Error> {
+    let res = File::create(path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "#EXTM3U")?;
+        for file in files {
+            writeln!(writer, "{}", absolute_path(mpaths, file).to_string_lossy())?;
+        }
+        writer.flush()
+    });
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::Config(format!("Failed to write playlist ({}). {}", path.to_string_lossy(), e))),
+    }
+}
+
+/// Create playlist of 'seed' followed by the 'count' tracks most similar to it.
+pub fn create(db_path: &str, mpaths: &Vec<PathBuf>, seed: &str, count: usize, out: &str, metric: Metric) -> Result<(), Error> {
+    let db = db::Db::new(&String::from(db_path))?;
+    let tracks = db.get_analysis();
+    db.close();
+
+    let mut seed = seed.to_string();
+    if cfg!(windows) {
+        seed = seed.replace("\\", "/");
+    }
+    let seed_track = match tracks.iter().find(|t| t.file == seed) {
+        Some(track) => track,
+        None => { return Err(Error::Config(format!("Seed track ({}) is not in database", seed))); }
+    };
+
+    let mut similar: Vec<(f64, &str)> = tracks
+        .iter()
+        .filter(|t| t.file != seed && playable(t))
+        .map(|t| (metric.distance(&seed_track.analysis, &t.analysis), t.file.as_str()))
+        .collect();
+    similar.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    similar.truncate(count);
+
+    let mut files: Vec<&str> = Vec::new();
+    if playable(seed_track) {
+        files.push(&seed_track.file);
+    }
+    files.extend(similar.iter().map(|s| s.1));
+    write_m3u(&PathBuf::from(out), mpaths, &files)?;
+    log::info!("Wrote {} track(s) to {}", files.len(), out);
+    Ok(())
+}
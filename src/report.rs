@@ -0,0 +1,114 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::error::Error;
+use serde_json::json;
+use std::fs;
+use std::sync::Mutex;
+
+// Report being collected, only set during a dry run
+static REPORT: Mutex<Option<DryRun>> = Mutex::new(None);
+
+/// Everything an analysis would do, collected during a dry run so that it can be shown
+/// as a single report rather than interleaved with other log messages.
+#[derive(Default)]
+pub struct DryRun {
+    // Tracks whose path would be updated, as (old path, new path)
+    moved: Vec<(String, String)>,
+    // Tracks (or CUE files) whose tracks would be removed, as (path, reason)
+    removed: Vec<(String, String)>,
+    analyse: Vec<String>,
+    cue: Vec<String>,
+    // Files and folders not analysed, as (path, reason)
+    skipped: Vec<(String, String)>,
+}
+
+impl DryRun {
+    fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        lines.push(format!("Tracks to update, as file has moved: {}", self.moved.len()));
+        lines.extend(self.moved.iter().map(|(old, new)| format!("  {} -> {}", old, new)));
+        lines.push(format!("Tracks to remove: {}", self.removed.len()));
+        lines.extend(self.removed.iter().map(|(path, reason)| format!("  {} ({})", path, reason)));
+        lines.push(format!("Files to analyse: {}", self.analyse.len()));
+        lines.extend(self.analyse.iter().map(|path| format!("  {}", path)));
+        lines.push(format!("CUE files to analyse: {}", self.cue.len()));
+        lines.extend(self.cue.iter().map(|path| format!("  {}", path)));
+        lines.push(format!("Skipped: {}", self.skipped.len()));
+        lines.extend(self.skipped.iter().map(|(path, reason)| format!("  {} ({})", path, reason)));
+        lines
+    }
+
+    pub fn log(&self) {
+        log::info!("Dry run report:");
+        for line in self.lines() {
+            log::info!("{}", line);
+        }
+    }
+
+    /// Write report to 'path', as JSON if this ends with '.json' - otherwise as text.
+    pub fn write(&self, path: &str) -> Result<(), Error> {
+        let contents = if path.to_ascii_lowercase().ends_with(".json") {
+            json!({
+                "moved": self.moved.iter().map(|(old, new)| json!({"from": old, "to": new})).collect::<Vec<_>>(),
+                "removed": self.removed.iter().map(|(path, reason)| json!({"path": path, "reason": reason})).collect::<Vec<_>>(),
+                "analyse": self.analyse,
+                "cue": self.cue,
+                "skipped": self.skipped.iter().map(|(path, reason)| json!({"path": path, "reason": reason})).collect::<Vec<_>>(),
+            }).to_string()
+        } else {
+            let mut text = self.lines().join("\n");
+            text.push('\n');
+            text
+        };
+        match fs::write(path, contents) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::Config(format!("Failed to write report file ({}). {}", path, e))),
+        }
+    }
+}
+
+fn add<F: FnOnce(&mut DryRun)>(f: F) {
+    if let Ok(mut report) = REPORT.lock() {
+        if let Some(report) = report.as_mut() {
+            f(report);
+        }
+    }
+}
+
+/// Start collecting report, the functions below are no-ops until this is called.
+pub fn start() {
+    if let Ok(mut report) = REPORT.lock() {
+        *report = Some(DryRun::default());
+    }
+}
+
+/// Stop collecting, and return, report.
+pub fn finish() -> Option<DryRun> {
+    REPORT.lock().ok().and_then(|mut report| report.take())
+}
+
+pub fn moved(old: &str, new: &str) {
+    add(|r| r.moved.push((old.to_string(), new.to_string())));
+}
+
+pub fn removed(path: &str, reason: &str) {
+    add(|r| r.removed.push((path.to_string(), reason.to_string())));
+}
+
+pub fn analyse(path: &str) {
+    add(|r| r.analyse.push(path.to_string()));
+}
+
+pub fn cue(path: &str) {
+    add(|r| r.cue.push(path.to_string()));
+}
+
+pub fn skipped(path: &str, reason: &str) {
+    add(|r| r.skipped.push((path.to_string(), reason.to_string())));
+}
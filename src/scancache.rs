@@ -0,0 +1,141 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+const CACHE_VERSION: u64 = 1;
+
+/// Entry of a folder listing.
+#[derive(Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub is_file: bool,
+    pub is_symlink: bool,
+}
+
+#[derive(Clone)]
+struct CachedDir {
+    mtime: u64,
+    // Name, is_dir, is_file, is_symlink
+    entries: Vec<(String, bool, bool, bool)>,
+}
+
+/// Read folder listing. Entries that are neither files nor folders (e.g. broken links)
+/// are not returned.
+pub fn read_dir(dir: &Path) -> Option<Vec<Entry>> {
+    let items = dir.read_dir().ok()?;
+    Some(items.flatten()
+        .map(|item| item.path())
+        .map(|path| Entry { is_dir: path.is_dir(), is_file: path.is_file(), is_symlink: path.is_symlink(), path: path })
+        .filter(|entry| entry.is_dir || entry.is_file)
+        .collect())
+}
+
+// Modification time of folder, in nanoseconds
+fn mtime(dir: &Path) -> Option<u64> {
+    let modified = fs::metadata(dir).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos() as u64)
+}
+
+/// Folder listings from a previous scan. A folder's modification time changes whenever
+/// an entry is added, removed, or renamed - so if this is unchanged the cached listing
+/// is used, saving reading the folder and checking the type of each entry.
+pub struct ScanCache {
+    file: String,
+    previous: HashMap<String, CachedDir>,
+    // Listings of folders read during this scan
+    current: Mutex<HashMap<String, CachedDir>>,
+}
+
+impl ScanCache {
+    /// Load cache from 'file'. If this does not exist, or is invalid, then the cache is
+    /// empty - and so all folders will be read.
+    pub fn load(file: &str) -> Self {
+        let mut previous: HashMap<String, CachedDir> = HashMap::new();
+        if let Ok(contents) = fs::read_to_string(file) {
+            match serde_json::from_str::<Value>(&contents) {
+                Ok(root) if root["version"].as_u64() == Some(CACHE_VERSION) => {
+                    if let Some(dirs) = root["dirs"].as_object() {
+                        for (dir, val) in dirs {
+                            if let Some(cached) = parse_dir(val) {
+                                previous.insert(dir.clone(), cached);
+                            }
+                        }
+                    }
+                }
+                _ => { log::warn!("Ignoring invalid scan cache ({})", file); }
+            }
+        }
+        log::debug!("Read {} folder(s) from scan cache", previous.len());
+        Self {
+            file: file.to_string(),
+            previous: previous,
+            current: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Listing of 'dir', from cache if folder is unchanged - otherwise read from disk.
+    pub fn read_dir(&self, dir: &Path) -> Option<Vec<Entry>> {
+        let key = dir.to_str()?.to_string();
+        let mtime = mtime(dir)?;
+        if let Some(cached) = self.previous.get(&key) {
+            if cached.mtime == mtime {
+                let entries = cached.entries.iter()
+                    .map(|(name, is_dir, is_file, is_symlink)| Entry { path: dir.join(name), is_dir: *is_dir, is_file: *is_file, is_symlink: *is_symlink })
+                    .collect();
+                self.current.lock().unwrap().insert(key, cached.clone());
+                return Some(entries);
+            }
+        }
+        let entries = read_dir(dir)?;
+        // Names that are not valid UTF-8 cannot be stored, so such folders are always read
+        let names: Option<Vec<(String, bool, bool, bool)>> = entries.iter()
+            .map(|e| e.path.file_name().and_then(|n| n.to_str()).map(|n| (n.to_string(), e.is_dir, e.is_file, e.is_symlink)))
+            .collect();
+        if let Some(names) = names {
+            self.current.lock().unwrap().insert(key, CachedDir { mtime: mtime, entries: names });
+        }
+        Some(entries)
+    }
+
+    /// Save listings read during this scan of 'path'. Listings of folders outside of
+    /// 'path' (e.g. other music folders) are kept, those within it that were not found
+    /// by this scan are removed.
+    pub fn save(&self, path: &Path) {
+        let current = self.current.lock().unwrap();
+        let mut dirs = serde_json::Map::new();
+        for (dir, cached) in self.previous.iter().filter(|(dir, _)| !Path::new(dir).starts_with(path)).chain(current.iter()) {
+            dirs.insert(dir.clone(), json!({
+                "mtime": cached.mtime,
+                "entries": cached.entries.iter().map(|(name, is_dir, is_file, is_symlink)| json!([name, is_dir, is_file, is_symlink])).collect::<Vec<Value>>(),
+            }));
+        }
+        let count = dirs.len();
+        let contents = json!({"version": CACHE_VERSION, "dirs": dirs}).to_string();
+        match fs::write(&self.file, contents) {
+            Ok(_) => { log::debug!("Wrote {} folder(s) to scan cache", count); }
+            Err(e) => { log::error!("Failed to write scan cache ({}). {}", self.file, e); }
+        }
+    }
+}
+
+fn parse_dir(val: &Value) -> Option<CachedDir> {
+    let mtime = val["mtime"].as_u64()?;
+    let mut entries: Vec<(String, bool, bool, bool)> = Vec::new();
+    for entry in val["entries"].as_array()? {
+        let entry = entry.as_array()?;
+        entries.push((entry.get(0)?.as_str()?.to_string(), entry.get(1)?.as_bool()?, entry.get(2)?.as_bool()?, entry.get(3)?.as_bool()?));
+    }
+    Some(CachedDir { mtime: mtime, entries: entries })
+}
@@ -0,0 +1,106 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use chrono::Local;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const MIN_WRITE_TIME: Duration = Duration::from_secs(2);
+
+pub const STATE_SCANNING: &str = "scanning";
+pub const STATE_ANALYSING: &str = "analysing";
+pub const STATE_FINISHED: &str = "finished";
+pub const STATE_ABORTED: &str = "aborted";
+pub const STATE_TERMINATED: &str = "terminated";
+
+/// JSON file, updated during analysis, so that progress can be monitored by other tools.
+pub struct StatusFile {
+    path: String,
+    start: Instant,
+    last_write: Option<Instant>,
+    found: u64,
+    done: u64,
+    failed: usize,
+    current: String,
+}
+
+impl StatusFile {
+    pub fn new(path: &str) -> Self {
+        let mut status = Self {
+            path: path.to_string(),
+            start: Instant::now(),
+            last_write: None,
+            found: 0,
+            done: 0,
+            failed: 0,
+            current: String::new(),
+        };
+        status.write(STATE_SCANNING, None);
+        status
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.path.is_empty()
+    }
+
+    /// Add number of files found in a music folder.
+    pub fn add_found(&mut self, count: usize) {
+        self.found += count as u64;
+    }
+
+    /// Called as each file is analysed, writes are throttled.
+    pub fn inc(&mut self, current: &str, failed: usize) {
+        self.done += 1;
+        self.failed = failed;
+        self.current = current.to_string();
+        if let Some(last) = self.last_write {
+            if last.elapsed() < MIN_WRITE_TIME {
+                return;
+            }
+        }
+        self.write(STATE_ANALYSING, None);
+    }
+
+    /// Write final state, and summary.
+    pub fn finish(&mut self, state: &str, summary: Value) {
+        self.current = String::new();
+        self.write(state, Some(summary));
+    }
+
+    // Write to temporary file, and rename, so that readers never see a partial file
+    fn write(&mut self, state: &str, summary: Option<Value>) {
+        if !self.enabled() {
+            return;
+        }
+        self.last_write = Some(Instant::now());
+        let elapsed = self.start.elapsed().as_secs();
+        let eta = if self.done > 0 && self.found > self.done && state == STATE_ANALYSING {
+            Some(elapsed * (self.found - self.done) / self.done)
+        } else {
+            None
+        };
+        let status = json!({
+            "state": state,
+            "updated": Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            "found": self.found,
+            "done": self.done,
+            "failed": self.failed,
+            "current": self.current,
+            "elapsed": elapsed,
+            "eta": eta,
+            "summary": summary,
+        });
+        let tmp = PathBuf::from(format!("{}.tmp", self.path));
+        let res = fs::write(&tmp, status.to_string()).and_then(|_| fs::rename(&tmp, &self.path));
+        if let Err(e) = res {
+            log::error!("Failed to write status file ({}). {}", self.path, e);
+        }
+    }
+}
@@ -7,8 +7,9 @@
  **/
 
 use crate::db;
-use lofty::{Accessor, AudioFile, ItemKey, TaggedFileExt};
+use lofty::{Accessor, AudioFile, ItemKey, Tag, TagExt, TaggedFileExt};
 use regex::Regex;
+use std::fs::{self, File};
 use std::path::Path;
 use substring::Substring;
 
@@ -20,10 +21,13 @@ pub fn read(track: &String) -> db::Metadata {
         ..db::Metadata::default()
     };
 
+    meta.size = fs::metadata(track).ok().map(|m| m.len() as i64);
     if let Ok(file) = lofty::read_from_path(Path::new(track)) {
-        let tag = match file.primary_tag() {
-            Some(primary_tag) => primary_tag,
-            None => file.first_tag().expect("Error: No tags found!"),
+        meta.duration = file.properties().duration().as_secs() as u32;
+        // Files such as WAV often have no tags at all
+        let tag = match file.primary_tag().or(file.first_tag()) {
+            Some(tag) => tag,
+            None => { return meta; }
         };
 
         meta.title = tag.title().unwrap_or_default().to_string();
@@ -31,6 +35,9 @@ pub fn read(track: &String) -> db::Metadata {
         meta.album = tag.album().unwrap_or_default().to_string();
         meta.album_artist = tag.get_string(&ItemKey::AlbumArtist).unwrap_or_default().to_string();
         meta.genre = tag.genre().unwrap_or_default().to_string();
+        meta.track_num = tag.track();
+        meta.disc = tag.disk();
+        meta.year = tag.year();
 
         // Check whether MP3 has numeric genre, and if so covert to text
         if file.file_type().eq(&lofty::FileType::Mpeg) {
@@ -69,9 +76,129 @@ pub fn read(track: &String) -> db::Metadata {
                 None => { }
             }
         }
-
-        meta.duration = file.properties().duration().as_secs() as u32;
     }
 
     meta
 }
+
+/// Title to use when a file has no tags, i.e. the file name without extension. For
+/// tracks of CUE files this is the name of the audio file, and the track number.
+pub fn title_from_path(path: &str) -> String {
+    let (path, track) = match path.find(db::CUE_MARKER) {
+        Some(pos) => (&path[..pos], Some(&path[pos + db::CUE_MARKER.len()..])),
+        None => (path, None),
+    };
+    let stem = Path::new(path).file_stem().unwrap_or_default().to_string_lossy();
+    match track {
+        Some(track) => format!("{} - {}", stem, track),
+        None => stem.to_string(),
+    }
+}
+
+/// If 'meta' is empty, i.e. file has no tags, set its title from 'path' - so that the
+/// DB entry is not blank. Returns true if title was set.
+pub fn title_if_empty(meta: &mut db::Metadata, path: &str) -> bool {
+    if !meta.is_empty() {
+        return false;
+    }
+    meta.title = title_from_path(path);
+    true
+}
+
+/// Write non-empty values of 'meta' (except duration) to file's primary tag, creating
+/// this if required. If 'preserve_mod_time' is set, the file's modification time is
+/// restored afterwards.
+pub fn write(track: &Path, meta: &db::Metadata, preserve_mod_time: bool) -> Result<(), String> {
+    let modified = if preserve_mod_time { track.metadata().and_then(|m| m.modified()).ok() } else { None };
+    let mut file = match lofty::read_from_path(track) {
+        Ok(file) => file,
+        Err(e) => { return Err(format!("Failed to read tags. {}", e)); }
+    };
+    if file.primary_tag_mut().is_none() {
+        let tag_type = file.primary_tag_type();
+        file.insert_tag(Tag::new(tag_type));
+    }
+    let tag = match file.primary_tag_mut() {
+        Some(tag) => tag,
+        None => { return Err(String::from("Failed to create tag")); }
+    };
+    if !meta.title.is_empty() {
+        tag.set_title(meta.title.clone());
+    }
+    if !meta.artist.is_empty() {
+        tag.set_artist(meta.artist.clone());
+    }
+    if !meta.album_artist.is_empty() {
+        tag.insert_text(ItemKey::AlbumArtist, meta.album_artist.clone());
+    }
+    if !meta.album.is_empty() {
+        tag.set_album(meta.album.clone());
+    }
+    if !meta.genre.is_empty() {
+        tag.set_genre(meta.genre.clone());
+    }
+    if let Err(e) = tag.save_to_path(track) {
+        return Err(format!("Failed to write tags. {}", e));
+    }
+    if let Some(modified) = modified {
+        if let Err(e) = File::options().write(true).open(track).and_then(|f| f.set_modified(modified)) {
+            return Err(format!("Failed to restore modification time. {}", e));
+        }
+    }
+    Ok(())
+}
+
+/// Read duration, in seconds, of audio file.
+pub fn duration(track: &Path) -> Option<u32> {
+    let file = lofty::read_from_path(track).ok()?;
+    Some(file.properties().duration().as_secs() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal 16-bit mono PCM WAV file, with no tags, of 'secs' seconds
+    fn wav(secs: u32) -> Vec<u8> {
+        let rate: u32 = 8000;
+        let data_len = rate * 2 * secs;
+        let mut data: Vec<u8> = Vec::new();
+        data.extend(b"RIFF");
+        data.extend((36 + data_len).to_le_bytes());
+        data.extend(b"WAVEfmt ");
+        data.extend(16u32.to_le_bytes());
+        data.extend(1u16.to_le_bytes()); // PCM
+        data.extend(1u16.to_le_bytes()); // Channels
+        data.extend(rate.to_le_bytes());
+        data.extend((rate * 2).to_le_bytes()); // Bytes per second
+        data.extend(2u16.to_le_bytes()); // Block align
+        data.extend(16u16.to_le_bytes()); // Bits per sample
+        data.extend(b"data");
+        data.extend(data_len.to_le_bytes());
+        data.resize(data.len() + data_len as usize, 0);
+        data
+    }
+
+    #[test]
+    fn tagless_wav_title() {
+        let dir = std::env::temp_dir().join(format!("bliss-analyser-test-{}-wav", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Clair de lune.wav");
+        let data = wav(2);
+        fs::write(&path, &data).unwrap();
+        let mut meta = read(&String::from(path.to_string_lossy()));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(meta.is_empty());
+        assert_eq!(meta.size, Some(data.len() as i64));
+        assert!(title_if_empty(&mut meta, "Debussy/Clair de lune.wav"));
+        assert_eq!(meta.title, "Clair de lune");
+        assert!(!title_if_empty(&mut meta, "Debussy/Other.wav"));
+        assert_eq!(meta.title, "Clair de lune");
+    }
+
+    #[test]
+    fn cue_track_title() {
+        assert_eq!(title_from_path(&format!("Debussy/Preludes.wav{}3", db::CUE_MARKER)), "Preludes - 3");
+    }
+}
@@ -6,35 +6,181 @@
  *
  **/
 
+use crate::error::Error;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::BufReader;
-use std::process;
+use std::io::{self, BufReader};
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use substring::Substring;
 use ureq;
 
-fn fail(msg: &str) {
-    log::error!("{}", msg);
-    process::exit(-1);
+pub struct Lms {
+    pub host: String,
+    pub json_port: u16,
+    pub scheme: String,
+    pub path: String,
+    pub user: String,
+    pub pass: String,
+    pub agent: ureq::Agent,
 }
 
-pub fn stop_mixer(lms: &String) {
-    let stop_req = "{\"id\":1, \"method\":\"slim.request\",\"params\":[\"\",[\"blissmixer\",\"stop\"]]}";
+/// Create agent to use for all network requests, with connect and read timeouts
+/// so that an unreachable server does not stall processing.
+pub fn agent(timeout: u64) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(timeout))
+        .timeout_read(Duration::from_secs(timeout))
+        .build()
+}
+
+impl Lms {
+    fn base_url(&self, port: u16) -> String {
+        format!("{}://{}:{}", self.scheme, self.host, port)
+    }
+
+    fn jsonrpc_url(&self) -> String {
+        format!("{}{}", self.base_url(self.json_port), self.path)
+    }
+
+    // Create request, adding basic-auth header if credentials have been supplied
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let req = self.agent.request(method, url);
+        if self.user.is_empty() {
+            req
+        } else {
+            let creds = base64::encode(format!("{}:{}", self.user, self.pass));
+            req.set("Authorization", &format!("Basic {}", creds))
+        }
+    }
+}
+
+// ID of next JSONRPC request, incremented so that requests can be matched in LMS's log
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// Build JSONRPC request body, for a command not specific to a player. Built via
+// serde_json, so that any values are correctly escaped.
+fn slim_request(command: Value) -> String {
+    let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    log::debug!("LMS request {}: {}", id, command);
+    json!({
+        "id": id,
+        "method": "slim.request",
+        "params": ["", command],
+    }).to_string()
+}
+
+const DISCOVERY_PORT: u16 = 3483;
+const DISCOVERY_REQ: &[u8] = b"eIPAD\0NAME\0JSON\0";
+const DISCOVERY_TIMEOUT: u64 = 2;
+
+/// Broadcast LMS discovery packet, and return address and JSONRPC port of the first
+/// server to reply.
+pub fn discover() -> Option<(String, u16)> {
+    log::info!("Looking for LMS");
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.set_broadcast(true).ok()?;
+    socket.set_read_timeout(Some(Duration::from_secs(DISCOVERY_TIMEOUT))).ok()?;
+    if let Err(e) = socket.send_to(DISCOVERY_REQ, ("255.255.255.255", DISCOVERY_PORT)) {
+        log::error!("Failed to send LMS discovery request. {}", e);
+        return None;
+    }
+
+    let mut buf = [0u8; 1024];
+    while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+        // Reply is 'E' followed by: 4 character tag, 1 byte length, value
+        if len < 1 || buf[0] != b'E' {
+            continue;
+        }
+        let mut pos = 1;
+        while pos + 5 <= len {
+            let tag = &buf[pos..pos + 4];
+            let val_len = buf[pos + 4] as usize;
+            let end = (pos + 5 + val_len).min(len);
+            let val = String::from_utf8_lossy(&buf[pos + 5..end]);
+            if tag == b"JSON" {
+                if let Ok(port) = val.parse::<u16>() {
+                    log::info!("Found LMS at {}:{}", addr.ip(), port);
+                    return Some((addr.ip().to_string(), port));
+                }
+            }
+            pos = end;
+        }
+    }
+    None
+}
+
+pub fn stop_mixer(lms: &Lms) -> Result<(), Error> {
+    let stop_req = slim_request(json!(["blissmixer", "stop"]));
 
     log::info!("Asking plugin to stop mixer");
-    let req = ureq::post(&format!("http://{}:9000/jsonrpc.js", lms)).send_string(&stop_req);
+    let req = lms.request("POST", &lms.jsonrpc_url()).send_string(&stop_req);
     if let Err(e) = req {
-        log::error!("Failed to ask plugin to stop mixer. {}", e);
+        return Err(Error::Lms(format!("Failed to ask plugin to stop mixer. {}", e)));
+    }
+    Ok(())
+}
+
+// Send JSONRPC request, and return its result
+fn jsonrpc(lms: &Lms, command: Value) -> Result<Value, Error> {
+    let req = slim_request(command);
+    match lms.request("POST", &lms.jsonrpc_url()).send_string(&req) {
+        Ok(resp) => match resp.into_string().map(|text| serde_json::from_str::<Value>(&text)) {
+            Ok(Ok(json)) => Ok(json["result"].clone()),
+            Ok(Err(e)) => Err(Error::Lms(format!("Failed to parse LMS response. {}", e))),
+            Err(e) => Err(Error::Lms(format!("Failed to read LMS response. {}", e))),
+        }
+        Err(e) => Err(Error::Lms(format!("Failed to contact LMS. {}", e))),
     }
 }
 
-pub fn upload_db(db_path: &String, lms: &String) {
+/// Get version of LMS, used to check that LMS is reachable.
+pub fn server_version(lms: &Lms) -> Result<String, Error> {
+    let result = jsonrpc(lms, json!(["version", "?"]))?;
+    match result["_version"].as_str() {
+        Some(version) => Ok(version.to_string()),
+        None => Err(Error::Lms("LMS did not return its version".to_string())),
+    }
+}
+
+/// Check if Bliss Mixer plugin is installed.
+pub fn plugin_available(lms: &Lms) -> Result<bool, Error> {
+    let result = jsonrpc(lms, json!(["can", "blissmixer", "?"]))?;
+    Ok(result["_can"].as_i64().unwrap_or(0) == 1 || result["_can"].as_str() == Some("1"))
+}
+
+const CHECKSUM_HEADER: &str = "X-Checksum-SHA256";
+
+fn file_checksum(path: &String) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn rescan(lms: &Lms) {
+    let rescan_req = slim_request(json!(["rescan", "playlists"]));
+
+    log::info!("Asking LMS to rescan");
+    match lms.request("POST", &lms.jsonrpc_url()).send_string(&rescan_req) {
+        Ok(resp) => match resp.into_string() {
+            Ok(text) => { log::info!("Rescan response: {}", text); }
+            Err(e) => { log::error!("Failed to read rescan response. {}", e); }
+        }
+        Err(e) => { log::error!("Failed to ask LMS to rescan. {}", e); }
+    }
+}
+
+pub fn upload_db(db_path: &String, lms: &Lms, rescan_after: bool) -> Result<(), Error> {
     // First tell LMS to restart the mixer in upload mode
-    let start_req = "{\"id\":1, \"method\":\"slim.request\",\"params\":[\"\",[\"blissmixer\",\"start-upload\"]]}";
-    let mut port: u16 = 0;
+    let start_req = slim_request(json!(["blissmixer", "start-upload"]));
+    let port: u16;
 
     log::info!("Requesting LMS plugin to allow uploads");
 
-    match ureq::post(&format!("http://{}:9000/jsonrpc.js", lms)).send_string(&start_req) {
+    match lms.request("POST", &lms.jsonrpc_url()).send_string(&start_req) {
         Ok(resp) => match resp.into_string() {
             Ok(text) => match text.find("\"port\":") {
                 Some(s) => {
@@ -45,23 +191,28 @@ pub fn upload_db(db_path: &String, lms: &String) {
                             let test = p.parse::<u16>();
                             match test {
                                 Ok(val) => { port = val; }
-                                Err(_) => { fail("Could not parse resp (cast)"); }
+                                Err(_) => { return Err(Error::Lms("Could not parse resp (cast)".to_string())); }
                             }
                         }
-                        None => { fail("Could not parse resp (closing)"); }
+                        None => { return Err(Error::Lms("Could not parse resp (closing)".to_string())); }
                     }
                 }
-                None => { fail("Could not parse resp (no port)"); }
+                None => { return Err(Error::Lms("Could not parse resp (no port)".to_string())); }
             }
-            Err(_) => fail("No text?"),
+            Err(_) => { return Err(Error::Lms("No text?".to_string())); }
         }
-        Err(e) => { fail(&format!("Failed to ask LMS plugin to allow upload. {}", e)); }
+        Err(e) => { return Err(Error::Lms(format!("Failed to ask LMS plugin to allow upload. {}", e))); }
     }
 
     if port == 0 {
-        fail("Invalid port");
+        return Err(Error::Lms("Invalid port".to_string()));
     }
 
+    let checksum = match file_checksum(db_path) {
+        Ok(sum) => sum,
+        Err(e) => { return Err(Error::Db(format!("Failed to calculate database checksum. {}", e))); }
+    };
+
     // Now we have port number, do the actual upload...
     log::info!("Uploading {}", db_path);
     match File::open(db_path) {
@@ -69,19 +220,50 @@ pub fn upload_db(db_path: &String, lms: &String) {
             Ok(meta) => {
                 let buffered_reader = BufReader::new(file);
                 log::info!("Length: {}", meta.len());
-                match ureq::put(&format!("http://{}:{}/upload", lms, port))
+                log::info!("SHA-256: {}", checksum);
+                match lms.request("PUT", &format!("{}/upload", lms.base_url(port)))
                     .set("Content-Length", &meta.len().to_string())
                     .set("Content-Type", "application/octet-stream")
+                    .set(CHECKSUM_HEADER, &checksum)
                     .send(buffered_reader) {
-                    Ok(_) => {
+                    Ok(resp) => {
+                        // If plugin echoes back checksum of what it received, then confirm this matches
+                        if let Some(remote) = resp.header(CHECKSUM_HEADER) {
+                            if !remote.trim().eq_ignore_ascii_case(&checksum) {
+                                return Err(Error::Lms(format!("Uploaded database is corrupt, checksum mismatch. Local: {}, remote: {}", checksum, remote)));
+                            }
+                            log::info!("Checksum verified");
+                        }
                         log::info!("Database uploaded");
-                        stop_mixer(lms);
+                        if let Err(e) = stop_mixer(lms) {
+                            log::error!("{}", e);
+                        }
+                        if rescan_after {
+                            rescan(lms);
+                        }
+                        Ok(())
                     }
-                    Err(e) => { fail(&format!("Failed to upload database. {}", e)); }
+                    Err(e) => { Err(Error::Lms(format!("Failed to upload database. {}", e))) }
                 }
             }
-            Err(e) => { fail(&format!("Failed to open database. {}", e)); }
+            Err(e) => { Err(Error::Db(format!("Failed to open database. {}", e))) }
         }
-        Err(e) => { fail(&format!("Failed to open database. {}", e)); }
+        Err(e) => { Err(Error::Db(format!("Failed to open database. {}", e))) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slim_request_escapes_path() {
+        let path = "Music/\"Weird Al\" Yankovic/Sigur Rós – Ágætis byrjun\\Svefn-g-englar\n.flac";
+        let first: Value = serde_json::from_str(&slim_request(json!(["blissmixer", "stop"]))).unwrap();
+        let req: Value = serde_json::from_str(&slim_request(json!(["blissmixer", "analyse", path]))).unwrap();
+        assert_eq!(req["method"], "slim.request");
+        assert_eq!(req["params"][0], "");
+        assert_eq!(req["params"][1][2].as_str(), Some(path));
+        assert!(req["id"].as_u64().unwrap() > first["id"].as_u64().unwrap());
     }
 }
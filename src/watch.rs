@@ -0,0 +1,112 @@
+/**
+ * Analyse music with Bliss
+ *
+ * Copyright (c) 2022-2023 Craig Drummond <craig.p.drummond@gmail.com>
+ * GPLv3 license.
+ *
+ **/
+
+use crate::analyse;
+use crate::error::Error;
+use crate::notify;
+use crate::upload;
+use fsnotify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+
+const POLL_TIME: Duration = Duration::from_secs(1);
+
+fn sorted(paths: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    let mut list: Vec<PathBuf> = paths.iter().cloned().collect();
+    list.sort();
+    list
+}
+
+// Upload errors are not fatal, as the next set of changes will cause another upload
+fn upload(db_path: &str, lms: Option<&upload::Lms>, rescan: bool) {
+    if let Some(lms) = lms {
+        if let Err(e) = upload::upload_db(&db_path.to_string(), lms, rescan) {
+            log::error!("{}", e);
+        }
+    }
+}
+
+/// Analyse any new files, and then keep monitoring music folders for changes. Once
+/// no changes have been seen for 'delay' seconds, the changed files are analysed.
+/// If 'lms' is set, then the DB is uploaded after each set of changes.
+pub fn watch(db_path: &str, mpaths: &Vec<PathBuf>, opts: &analyse::Options, notifier: &mut notify::Notifier, delay: u64, lms: Option<&upload::Lms>, rescan: bool) -> Result<(), Error> {
+    // Event paths are absolute, so music paths need to be too
+    let mpaths: Vec<PathBuf> = mpaths.iter().map(|p| p.canonicalize().unwrap_or(p.clone())).collect();
+
+    analyse::analyse_files(db_path, &mpaths, opts, notifier)?;
+    if analyse::terminated() {
+        return Ok(());
+    }
+    upload(db_path, lms, rescan);
+
+    let (tx, rx) = channel();
+    let mut fs_watcher = match watcher(tx, Duration::from_secs(delay)) {
+        Ok(w) => w,
+        Err(e) => {
+            return Err(Error::Config(format!("Failed to create file watcher. {}", e)));
+        }
+    };
+    for mpath in &mpaths {
+        if let Err(e) = fs_watcher.watch(mpath, RecursiveMode::Recursive) {
+            return Err(Error::Config(format!("Failed to watch music path ({}). {}", mpath.to_string_lossy(), e)));
+        }
+    }
+
+    log::info!("Watching for changes");
+    let mut changed: HashSet<PathBuf> = HashSet::new();
+    let mut removed: HashSet<PathBuf> = HashSet::new();
+    while !analyse::terminated() {
+        match rx.recv_timeout(POLL_TIME) {
+            Ok(event) => match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
+                    removed.remove(&path);
+                    changed.insert(path);
+                }
+                DebouncedEvent::Remove(path) => {
+                    changed.remove(&path);
+                    removed.insert(path);
+                }
+                DebouncedEvent::Rename(from, to) => {
+                    changed.remove(&from);
+                    removed.insert(from);
+                    removed.remove(&to);
+                    changed.insert(to);
+                }
+                DebouncedEvent::Rescan => {
+                    log::warn!("File system events were missed, rescanning music folders");
+                    changed.extend(mpaths.iter().cloned());
+                }
+                DebouncedEvent::Error(e, path) => {
+                    match path {
+                        Some(path) => { log::error!("Error watching '{}'. {}", path.to_string_lossy(), e); }
+                        None => { log::error!("Error watching music folders. {}", e); }
+                    }
+                }
+                _ => { }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if !changed.is_empty() || !removed.is_empty() {
+                    let modified = analyse::analyse_changed(db_path, &mpaths, &sorted(&changed), &sorted(&removed), opts, notifier)?;
+                    changed.clear();
+                    removed.clear();
+                    if modified && !analyse::terminated() {
+                        upload(db_path, lms, rescan);
+                    }
+                    log::info!("Watching for changes");
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                log::error!("File watcher stopped");
+                break;
+            }
+        }
+    }
+    Ok(())
+}